@@ -0,0 +1,170 @@
+//! `--archive`: read kallsyms and traces straight out of a capture tarball.
+//!
+//! Only the parts of tar that capture tools write are understood: regular
+//! files, with ustar prefixes or GNU long names for long paths. Gzipped
+//! archives are decompressed by running `gzip -dc`.
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use Archived;
+
+const BLOCK: usize = 512;
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Read the members named `kallsyms` and `ftrace-ramoops-*`, in any
+/// directory of the archive. Traces keep their order in the archive.
+pub fn read_archive(path: &str) -> io::Result<Archived> {
+    let mut data = fs::read(path)?;
+    if data.starts_with(GZIP_MAGIC) {
+        data = gunzip(data)?;
+    }
+    let mut archived = Archived::default();
+    for (name, contents) in members(&data)? {
+        let file = Path::new(&name).file_name().and_then(|f| f.to_str()).unwrap_or("");
+        if file == "kallsyms" {
+            archived.kallsyms.push((format!("{}:{}", path, name), contents));
+        } else if file.starts_with("ftrace-ramoops-") {
+            archived.traces.push((format!("{}:{}", path, name), contents));
+        }
+    }
+    Ok(archived)
+}
+
+fn gunzip(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut child = Command::new("gzip")
+        .arg("-dc")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    // Feed gzip from another thread so a full stdout pipe can't deadlock us
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || stdin.write_all(&data));
+    let output = child.wait_with_output()?;
+    writer.join().unwrap()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("gzip -dc failed: {}", output.status)));
+    }
+    Ok(output.stdout)
+}
+
+/// The regular files in a tar archive as (path, contents)
+fn members(data: &[u8]) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut members = Vec::new();
+    let mut long_name = None;
+    let mut pos = 0;
+    while pos + BLOCK <= data.len() {
+        let header = &data[pos..pos + BLOCK];
+        // The archive ends with zeroed blocks
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let size = octal(&header[124..136])
+            .ok_or_else(|| invalid(format!("bad member size at offset {}", pos)))?;
+        let start = pos + BLOCK;
+        let end = start.checked_add(size).filter(|&end| end <= data.len())
+            .ok_or_else(|| invalid(format!("member at offset {} is truncated", pos)))?;
+        let contents = &data[start..end];
+        match header[156] {
+            b'0' | 0 => {
+                let name = long_name.take().unwrap_or_else(|| header_name(header));
+                members.push((name, contents.to_vec()));
+            }
+            // GNU long name for the next member
+            b'L' => long_name = Some(field(contents)),
+            // Directories, links, pax headers and the like carry no traces
+            _ => long_name = None,
+        }
+        pos = start + size.div_ceil(BLOCK) * BLOCK;
+    }
+    Ok(members)
+}
+
+/// The member's path, joining the ustar prefix if there is one
+fn header_name(header: &[u8]) -> String {
+    let name = field(&header[0..100]);
+    let ustar = &header[257..262] == b"ustar";
+    let prefix = if ustar { field(&header[345..500]) } else { String::new() };
+    if prefix.is_empty() {
+        name
+    } else {
+        format!("{}/{}", prefix, name)
+    }
+}
+
+/// A NUL-terminated string field
+fn field(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+/// A numeric field in octal, padded with spaces or NULs
+fn octal(bytes: &[u8]) -> Option<usize> {
+    let text = field(bytes);
+    let text = text.trim_matches(' ');
+    if text.is_empty() {
+        return Some(0);
+    }
+    usize::from_str_radix(text, 8).ok()
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A header block for a member, as GNU tar writes them minus the checksum
+    fn header(name: &str, prefix: &str, size: usize, kind: u8) -> Vec<u8> {
+        let mut header = vec![0; BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let size = format!("{:011o}", size);
+        header[124..135].copy_from_slice(size.as_bytes());
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        header
+    }
+
+    /// A member's header and contents, padded to whole blocks
+    fn member(name: &str, prefix: &str, kind: u8, contents: &[u8]) -> Vec<u8> {
+        let mut data = header(name, prefix, contents.len(), kind);
+        data.extend_from_slice(contents);
+        data.resize(data.len().div_ceil(BLOCK) * BLOCK, 0);
+        data
+    }
+
+    fn names(members: &[(String, Vec<u8>)]) -> Vec<(&str, &[u8])> {
+        members.iter().map(|(name, contents)| (name.as_str(), &contents[..])).collect()
+    }
+
+    #[test]
+    fn ustar_members() {
+        let long = format!("capture/{}/ftrace-ramoops-0", "d".repeat(120));
+        let mut data = member("kallsyms", "capture/boot", b'0', b"1000 T foo\n");
+        data.extend(member("capture/boot", "", b'5', b""));
+        data.extend(member("././@LongLink", "", b'L', format!("{}\0", long).as_bytes()));
+        data.extend(member("capture/ddddddd", "", b'0', &[b'x'; 600]));
+        // The end of the archive, then junk that mustn't be read
+        data.extend(vec![0; 2 * BLOCK]);
+        data.extend(member("after-the-end", "", b'0', b""));
+        let members = members(&data).unwrap();
+        assert_eq!(names(&members), vec![("capture/boot/kallsyms", &b"1000 T foo\n"[..]),
+                                         (long.as_str(), &[b'x'; 600][..])]);
+    }
+
+    #[test]
+    fn truncated_member() {
+        let mut data = member("ftrace-ramoops-0", "", b'0', &[b'x'; 1000]);
+        data.truncate(BLOCK + 600);
+        let e = members(&data).err().unwrap();
+        assert_eq!(e.to_string(), "member at offset 0 is truncated");
+        assert_eq!(octal(b"0000001750 \0"), Some(1000));
+        assert_eq!(octal(b"12x"), None);
+    }
+}
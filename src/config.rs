@@ -0,0 +1,281 @@
+//! Default options from `ftrace-ramoops-fmt.toml` and the environment.
+//!
+//! Each key is an option without its leading dashes, read as if it had been
+//! given on the command line:
+//!
+//!     kallsyms = "/boot/System.map"
+//!     format = "ftrace"
+//!     exported-only = true
+//!     highlight = ["vfs_read", "ksys_read"]
+//!
+//! Only the part of TOML that can say that is understood: strings, integers,
+//! floats, booleans and flat arrays of strings or numbers, one key per line.
+//! Booleans are only accepted for the options that take no value, and the
+//! other types only for those that do.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+const CONFIG_FILE: &str = "ftrace-ramoops-fmt.toml";
+/// Options in the environment, split on whitespace like the command line
+const ENV_VAR: &str = "FTRACE_RAMOOPS_FMT_OPTS";
+/// The options `Options::parse` takes without a value
+const SWITCHES: &[&str] = &[
+    "--anonymize", "--ascii-tree", "--byteswap", "--count-only", "--cpu-hex", "--cross-module",
+    "--exported-only", "--fold-recursion", "--header", "--hide-ftrace-internals", "--inlines",
+    "--lazy", "--merge-suffixes", "--mmap", "--offset-detail", "--pager", "--quiet", "--raw",
+    "--report-dupes", "--resolve-from-only", "--resolve-to-only", "--sanity", "--sort-time",
+    "--stats", "--time-by-symbol", "--tree", "--watch",
+];
+
+/// The command line with the defaults from the config file and `ENV_VAR`
+/// put in front of it. An option given on the command line replaces every
+/// default for it, and one in the environment those from the config file,
+/// so repeatable options like `--highlight` aren't added to the defaults.
+/// `--no-config` skips both.
+///
+/// Default `kallsyms` are returned apart from the arguments, since whether
+/// they're used depends on the positional arguments, see `Options::parse`.
+pub fn with_defaults(cli: Vec<String>) -> (Vec<String>, Vec<String>) {
+    if cli.iter().any(|arg| arg == "--no-config") {
+        return (cli.into_iter().filter(|arg| arg != "--no-config").collect(), Vec::new());
+    }
+    let mut cli = cli.into_iter().peekable();
+    // The subcommand has to stay first
+    let command = cli.next_if(|arg| arg == "serve");
+    let cli: Vec<String> = cli.collect();
+    let env: Vec<String> = env::var(ENV_VAR).unwrap_or_default()
+        .split_whitespace().map(str::to_string).collect();
+    let config = config_path().map_or_else(Vec::new, |path| {
+        let text = fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Failed to read {}: {}", path.display(), e));
+        parse(&text).unwrap_or_else(|e| panic!("{}:{}", path.display(), e))
+    });
+
+    let env_entries = entries(env);
+    let given = |entries: &[Vec<String>], flag: &str| entries.iter().any(|e| e[0] == flag);
+    let cli_entries = entries(cli.clone());
+    let mut args: Vec<String> = command.into_iter().collect();
+    let mut kallsyms = Vec::new();
+    let mut add = |entry: Vec<String>| match entry[0].as_str() {
+        "--kallsyms" => kallsyms.extend(entry.into_iter().skip(1)),
+        _ => args.extend(entry),
+    };
+    for entry in config {
+        if !given(&cli_entries, &entry[0]) && !given(&env_entries, &entry[0]) {
+            add(entry);
+        }
+    }
+    for entry in env_entries {
+        if !given(&cli_entries, &entry[0]) {
+            add(entry);
+        }
+    }
+    args.extend(cli);
+    (args, kallsyms)
+}
+
+/// The config file in the working directory, else the one in
+/// `$XDG_CONFIG_HOME`, or `~/.config` if that's unset
+fn config_path() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    let mut candidates = vec![PathBuf::from(CONFIG_FILE)];
+    candidates.extend(config_home.map(|dir| dir.join(CONFIG_FILE)));
+    candidates.into_iter().find(|path| path.is_file())
+}
+
+/// Arguments grouped by the option each starts with, values following
+fn entries(args: Vec<String>) -> Vec<Vec<String>> {
+    let mut entries: Vec<Vec<String>> = Vec::new();
+    for arg in args {
+        match entries.last_mut() {
+            Some(entry) if !arg.starts_with("--") => entry.push(arg),
+            _ => entries.push(vec![arg]),
+        }
+    }
+    entries
+}
+
+/// The options in a config file, each as the arguments it stands for. False
+/// leaves an option out, true gives the flag alone, and an array repeats the
+/// flag for each value.
+fn parse(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut entries = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |e: &str| format!("{}: {}", n + 1, e);
+        let (key, value) = line.split_once('=').ok_or_else(|| error("expected key = value"))?;
+        let key = key.trim();
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(error("expected an option name as the key"));
+        }
+        let flag = format!("--{}", key);
+        let (value, rest) = parse_value(value.trim_start()).map_err(|e| error(&e))?;
+        let rest = rest.trim_start();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return Err(error("unexpected text after the value"));
+        }
+        let switch = SWITCHES.contains(&flag.as_str());
+        match value {
+            Value::Bool(_) if !switch => {
+                return Err(error(&format!("{} takes a value, not true or false", key)))
+            }
+            Value::Scalar(_) | Value::Array(_) if switch => {
+                return Err(error(&format!("{} takes no value, set it to true or false", key)))
+            }
+            Value::Bool(false) => {}
+            Value::Bool(true) => entries.push(vec![flag]),
+            Value::Scalar(value) => entries.push(vec![flag, value]),
+            Value::Array(values) => {
+                entries.extend(values.into_iter().map(|value| vec![flag.clone(), value]))
+            }
+        }
+    }
+    Ok(entries)
+}
+
+enum Value {
+    Bool(bool),
+    Scalar(String),
+    Array(Vec<String>),
+}
+
+/// The value at the start of `s` and what follows it
+fn parse_value(s: &str) -> Result<(Value, &str), String> {
+    if let Some(rest) = s.strip_prefix("true") {
+        return Ok((Value::Bool(true), rest));
+    }
+    if let Some(rest) = s.strip_prefix("false") {
+        return Ok((Value::Bool(false), rest));
+    }
+    let Some(mut rest) = s.strip_prefix('[') else {
+        let (value, rest) = parse_scalar(s)?;
+        return Ok((Value::Scalar(value), rest));
+    };
+    let mut values = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if let Some(rest) = rest.strip_prefix(']') {
+            return Ok((Value::Array(values), rest));
+        }
+        let (value, after) = parse_scalar(rest)?;
+        values.push(value);
+        rest = after.trim_start();
+        match rest.strip_prefix(',') {
+            Some(after) => rest = after,
+            None if rest.starts_with(']') => {}
+            None => return Err("expected , or ] in array".to_string()),
+        }
+    }
+}
+
+/// A string or number at the start of `s` and what follows it
+fn parse_scalar(s: &str) -> Result<(String, &str), String> {
+    if let Some(literal) = s.strip_prefix('\'') {
+        let end = literal.find('\'').ok_or("unterminated string")?;
+        return Ok((literal[..end].to_string(), &literal[end + 1..]));
+    }
+    if let Some(basic) = s.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = basic.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((value, &basic[i + 1..])),
+                '\\' => value.push(match chars.next().map(|(_, c)| c) {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    _ => return Err("unsupported escape in string".to_string()),
+                }),
+                c => value.push(c),
+            }
+        }
+        return Err("unterminated string".to_string());
+    }
+    let end = s.find(|c: char| {
+        !(c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '+' || c == '.')
+    }).unwrap_or(s.len());
+    let number = s[..end].replace('_', "");
+    let value = number.parse::<i64>().map(|n| n.to_string())
+        .or_else(|_| {
+            // Hex integers, e.g. for --kaslr-offset
+            match number.strip_prefix("0x") {
+                Some(hex) if u64::from_str_radix(hex, 16).is_ok() => Ok(number.clone()),
+                _ => Err(()),
+            }
+        })
+        .or_else(|_| {
+            // Floats, e.g. for --slow. Rust also parses words like inf,
+            // which TOML spells differently.
+            let digits = number.trim_start_matches(['+', '-']);
+            match number.parse::<f64>() {
+                Ok(_) if digits.starts_with(|c: char| c.is_ascii_digit()) => Ok(number.clone()),
+                _ => Err(()),
+            }
+        })
+        .map_err(|_| format!("expected a string, number, boolean or array, got {}", s))?;
+    Ok((value, &s[end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(text: &str) -> Vec<Vec<String>> {
+        parse(text).unwrap()
+    }
+
+    fn error(text: &str) -> String {
+        parse(text).err().unwrap()
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(args("kallsyms = \"/boot/System.map\"\narrow = '\\t'\n"),
+                   vec![vec!["--kallsyms", "/boot/System.map"], vec!["--arrow", "\\t"]]);
+        assert_eq!(args("arrow = \"a\\tb\\\"\\\\\\n\""), vec![vec!["--arrow", "a\tb\"\\\n"]]);
+        assert_eq!(args("highlight = [\"vfs_read\", 'ksys_read',]"),
+                   vec![vec!["--highlight", "vfs_read"], vec!["--highlight", "ksys_read"]]);
+        assert_eq!(args("none-addr = [ 0xffff , 1_000 ]"),
+                   vec![vec!["--none-addr", "0xffff"], vec!["--none-addr", "1000"]]);
+        assert_eq!(args("kaslr-offset = 0x1000000\nmax-line-len = 4_096\nslow = 0.5\n"),
+                   vec![vec!["--kaslr-offset", "0x1000000"], vec!["--max-line-len", "4096"],
+                        vec!["--slow", "0.5"]]);
+        assert_eq!(args("quiet = true\nstats = false\n"), vec![vec!["--quiet"]]);
+    }
+
+    #[test]
+    fn comments() {
+        let text = "# defaults\n\n  quiet = true  # no progress\narrow = \"#\" # a hash\n";
+        assert_eq!(args(text), vec![vec!["--quiet"], vec!["--arrow", "#"]]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("quiet"), "1: expected key = value");
+        assert_eq!(error("\nhigh light = 'x'"), "2: expected an option name as the key");
+        assert_eq!(error("arrow = \"->"), "1: unterminated string");
+        assert_eq!(error("arrow = '->"), "1: unterminated string");
+        assert_eq!(error("arrow = \"\\x\""), "1: unsupported escape in string");
+        assert_eq!(error("highlight = [\"a\" \"b\"]"), "1: expected , or ] in array");
+        assert_eq!(error("slow = 0.5 ms"), "1: unexpected text after the value");
+        assert_eq!(error("slow = inf"), "1: expected a string, number, boolean or array, got inf");
+        assert_eq!(error("quiet = 1"), "1: quiet takes no value, set it to true or false");
+        assert_eq!(error("tree = [\"a\"]"), "1: tree takes no value, set it to true or false");
+        assert_eq!(error("slow = true"), "1: slow takes a value, not true or false");
+    }
+
+    #[test]
+    fn option_entries() {
+        let cli = ["--quiet", "--highlight", "a", "--arrow", "->", "trace"];
+        assert_eq!(entries(cli.iter().map(|arg| arg.to_string()).collect()),
+                   vec![vec!["--quiet"], vec!["--highlight", "a"], vec!["--arrow", "->", "trace"]]);
+    }
+}
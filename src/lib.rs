@@ -0,0 +1,1915 @@
+//! Parsing and symbol resolution for pstore ftrace-ramoops dumps.
+extern crate regex;
+
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::fmt;
+use std::fmt::Display;
+use regex::{Captures, Regex};
+
+#[derive(Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub module: Option<String>,
+    /// kallsyms type letter; uppercase for global symbols
+    pub sym_type: char,
+    /// Size in bytes, when kallsyms has a size column
+    pub size: Option<u64>,
+}
+
+impl Symbol {
+    pub fn is_exported(&self) -> bool {
+        self.sym_type.is_ascii_uppercase()
+    }
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref m) = self.module {
+            write!(f, "{}[{}]", self.name, m)
+        } else {
+            write!(f, "{}", self.name)
+        }
+    }
+}
+
+/// Longest input line accepted by default. Real kallsyms and trace lines are
+/// well under this but a corrupt ramoops buffer can run on for megabytes.
+pub const DEFAULT_MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Options shared by the input parsers
+pub struct ParseOpts {
+    /// Lines longer than this many bytes are skipped with a warning
+    pub max_line_len: usize,
+    /// Trace CPU numbers are in hex rather than decimal
+    pub cpu_hex: bool,
+}
+
+impl Default for ParseOpts {
+    fn default() -> ParseOpts {
+        ParseOpts {
+            max_line_len: DEFAULT_MAX_LINE_LEN,
+            cpu_hex: false,
+        }
+    }
+}
+
+/// Like `BufRead::lines()`, but lines over a maximum length are skipped with
+/// a warning. At most the maximum plus one buffer's worth of a long line is
+/// held in memory.
+pub struct BoundedLines<F> {
+    f: F,
+    max: usize,
+    line_no: usize,
+    skipped: usize,
+}
+
+pub fn bounded_lines<F: BufRead>(f: F, opts: &ParseOpts) -> BoundedLines<F> {
+    BoundedLines {
+        f,
+        max: opts.max_line_len,
+        line_no: 0,
+        skipped: 0,
+    }
+}
+
+impl<F: BufRead> Iterator for BoundedLines<F> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let mut line = Vec::new();
+        let mut too_long = false;
+        loop {
+            let (used, end) = {
+                let buf = self.f.fill_buf().unwrap();
+                if buf.is_empty() {
+                    if line.is_empty() && !too_long {
+                        return None;
+                    }
+                    (0, true)
+                } else {
+                    let (len, end) = match buf.iter().position(|&b| b == b'\n') {
+                        Some(pos) => (pos, true),
+                        None => (buf.len(), false),
+                    };
+                    if !too_long {
+                        line.extend_from_slice(&buf[..len]);
+                    }
+                    (if end { len + 1 } else { len }, end)
+                }
+            };
+            self.f.consume(used);
+            if line.len() > self.max {
+                too_long = true;
+                line.clear();
+            }
+            if end {
+                self.line_no += 1;
+                if too_long {
+                    eprintln!("Warning: skipping line {} longer than {} bytes",
+                              self.line_no, self.max);
+                    self.skipped += 1;
+                    too_long = false;
+                    continue;
+                }
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                // Crash buffers can hold garbage bytes. Replace them rather
+                // than fail, so the line just doesn't match.
+                return Some(String::from_utf8(line).unwrap_or_else(|e| {
+                    String::from_utf8_lossy(e.as_bytes()).into_owned()
+                }));
+            }
+        }
+    }
+}
+
+/// Line counts from parsing an input
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ParseStats {
+    /// Lines read, including skipped ones
+    pub total_lines: usize,
+    /// Lines parsed into a symbol or call
+    pub matched: usize,
+    /// Lines skipped unread for being longer than `max_line_len`
+    pub skipped: usize,
+    /// Lines that didn't parse, skipped with a warning
+    pub errors: usize,
+}
+
+impl ParseStats {
+    /// Add another input's counts to these
+    pub fn add(&mut self, other: &ParseStats) {
+        self.total_lines += other.total_lines;
+        self.matched += other.matched;
+        self.skipped += other.skipped;
+        self.errors += other.errors;
+    }
+
+    fn from_lines<F>(lines: &BoundedLines<F>, matched: usize, errors: usize) -> ParseStats {
+        ParseStats {
+            total_lines: lines.line_no,
+            matched,
+            skipped: lines.skipped,
+            errors,
+        }
+    }
+}
+
+pub fn kallsyms<F: BufRead>(f: F, opts: &ParseOpts) -> Syms {
+    kallsyms_with_stats(f, opts).0
+}
+
+/// Like `kallsyms`, also counting the lines read
+pub fn kallsyms_with_stats<F: BufRead>(f: F, opts: &ParseOpts) -> (Syms, ParseStats) {
+    let (syms, _, stats) = parse_kallsyms(f, opts);
+    (syms, stats)
+}
+
+/// Names sharing an address, in file order. Later lines replace earlier ones,
+/// so the last name is the one kept in `Syms`.
+pub type Dupes = BTreeMap<u64, Vec<String>>;
+
+pub fn kallsyms_with_dupes<F: BufRead>(f: F, opts: &ParseOpts) -> (Syms, Dupes, ParseStats) {
+    parse_kallsyms(f, opts)
+}
+
+/// Lines that aren't symbols are skipped with a warning and counted as errors
+fn parse_kallsyms<F: BufRead>(f: F, opts: &ParseOpts) -> (Syms, Dupes, ParseStats) {
+    let regex = symbol_regex();
+    let mut syms = Syms::new();
+    let mut dupes = Dupes::new();
+    let (mut matched, mut errors) = (0, 0);
+    let mut lines = bounded_lines(f, opts);
+    for line in &mut lines {
+        let (addr, sym) = match parse_symbol(&regex, &line) {
+            Some(parsed) => parsed,
+            None => {
+                errors += 1;
+                continue;
+            }
+        };
+        matched += 1;
+        let kept = sym.to_string();
+        if let Some(old) = syms.insert(addr, sym) {
+            dupes.entry(addr).or_insert_with(|| vec![old.to_string()]).push(kept);
+        }
+    }
+    let stats = ParseStats::from_lines(&lines, matched, errors);
+    (syms, dupes, stats)
+}
+
+/// Like `kallsyms`, but only keep the symbols needed to resolve `targets`:
+/// the closest one at or below each target, the next one above it for its
+/// size, and the percpu bounds. The others are dropped after reading just
+/// their address and type, and only count as matched if kept. With
+/// `exported_only`, only global symbols count as the closest below.
+pub fn kallsyms_near<F: BufRead>(f: F, opts: &ParseOpts, targets: &[u64], exported_only: bool)
+                                 -> (Syms, ParseStats) {
+    let regex = symbol_regex();
+    let mut targets = targets.to_vec();
+    targets.sort();
+    targets.dedup();
+    // Best symbol address found so far below and above each target
+    let mut below: Vec<Option<u64>> = vec![None; targets.len()];
+    let mut above: Vec<Option<u64>> = vec![None; targets.len()];
+    let mut syms = Syms::new();
+    let (mut matched, mut errors) = (0, 0);
+    let mut lines = bounded_lines(f, opts);
+    for line in &mut lines {
+        let mut fields: Vec<&str> = line.split_whitespace().take(4).collect();
+        // Skip the size column if there is one, the same way the regex does
+        let is_hex = |field: &str| field.bytes().all(|b| b.is_ascii_hexdigit());
+        let is_type = |field: &str| field.len() == 1 && field.as_bytes()[0].is_ascii_alphabetic();
+        if fields.len() == 4 && is_hex(fields[1]) && is_type(fields[2]) {
+            fields.remove(1);
+        }
+        let addr = fields.first().and_then(|addr| u64::from_str_radix(addr, 16).ok());
+        let sym_type = fields.get(1).and_then(|t| t.chars().next());
+        let (addr, sym_type, name) = match (addr, sym_type, fields.get(2)) {
+            (Some(addr), Some(sym_type), Some(&name)) => (addr, sym_type, name),
+            _ => {
+                eprintln!("Warning: skipping unparsed kallsyms line: {}", line);
+                errors += 1;
+                continue;
+            }
+        };
+        let mut keep = name == "__per_cpu_start" || name == "__per_cpu_end";
+
+        // Targets are sorted, so the best symbols only get closer moving away
+        // from this one and the first target not improved ends the search.
+        // Later duplicates replace earlier ones, as in `kallsyms`.
+        let first_above = targets.partition_point(|&target| target < addr);
+        if !exported_only || sym_type.is_ascii_uppercase() {
+            for best in &mut below[first_above..] {
+                if best.is_some_and(|best| best > addr) {
+                    break;
+                }
+                *best = Some(addr);
+                keep = true;
+            }
+        }
+        for best in above[..first_above].iter_mut().rev() {
+            if best.is_some_and(|best| best < addr) {
+                break;
+            }
+            *best = Some(addr);
+            keep = true;
+        }
+
+        if keep {
+            match parse_symbol(&regex, &line) {
+                Some((addr, sym)) => {
+                    syms.insert(addr, sym);
+                    matched += 1;
+                }
+                None => errors += 1,
+            }
+        }
+    }
+    let stats = ParseStats::from_lines(&lines, matched, errors);
+    (syms, stats)
+}
+
+fn symbol_regex() -> Regex {
+    let regex = r"(?x)
+        (?P<addr>[0-9a-fA-F]+)\s    # Address
+        (?:(?P<size>[0-9a-fA-F]+)\s+)? # Optional size
+        (?P<type>[:alpha:])\s       # Type
+        (?P<name>\S.*?)             # Name, which may have spaces and brackets
+        (?:\s+\[(?P<mod>[^\]]+)\])? # Optional module, only as the last token
+        \s*$
+        ";
+    Regex::new(regex).unwrap()
+}
+
+/// Parse a kallsyms line, warning if it isn't one
+fn parse_symbol(regex: &Regex, line: &str) -> Option<(u64, Symbol)> {
+    let caps = match regex.captures(line) {
+        Some(caps) => caps,
+        None => {
+            eprintln!("Warning: skipping unparsed kallsyms line: {}", line);
+            return None;
+        }
+    };
+    let addr = caps.name("addr").unwrap();
+    let addr = u64::from_str_radix(addr, 16).expect("Failed to parse address");
+    let name = caps.name("name").unwrap().to_string();
+    let module = caps.name("mod").map(|x| x.to_string());
+    let sym_type = caps.name("type").unwrap().chars().next().unwrap();
+    let size = caps.name("size").map(|size| {
+        u64::from_str_radix(size, 16).expect("Failed to parse symbol size")
+    });
+    let sym = Symbol {
+        name,
+        module,
+        sym_type,
+        size,
+    };
+    Some((addr, sym))
+}
+
+pub type Syms = BTreeMap<u64, Symbol>;
+
+/// Suffixes the compiler appends to split or specialized copies of a
+/// function. `.part`, `.constprop` and `.isra` are followed by a number,
+/// e.g. `foo.constprop.0`.
+pub const CLONE_SUFFIXES: &[&str] = &["cold", "part", "constprop", "isra"];
+/// Prefix of the padding symbols emitted before functions with
+/// CONFIG_FUNCTION_PADDING_CFI.
+pub const PADDING_PREFIX: &str = "__pfx_";
+
+/// Strip compiler clone suffixes and the padding prefix, so `foo.cold`,
+/// `foo.isra.0.cold` and `__pfx_foo` all become `foo`. Names with any other
+/// dotted component are left alone.
+pub fn merge_suffixes(name: &str) -> &str {
+    let name = name.strip_prefix(PADDING_PREFIX).unwrap_or(name);
+    let mut parts = name.split('.');
+    let base = parts.next().unwrap();
+    let is_clone = |part: &str| {
+        CLONE_SUFFIXES.contains(&part) ||
+            (!part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+    };
+    if !base.is_empty() && parts.all(is_clone) { base } else { name }
+}
+
+/// Symbol name prefixes and the module to show for matching symbols, longest
+/// prefix first.
+pub type ModuleMap = Vec<(String, String)>;
+
+/// Read a module map: one `prefix module` pair per line. Blank lines and
+/// lines starting with `#` are ignored.
+pub fn read_module_map<F: BufRead>(f: F) -> io::Result<ModuleMap> {
+    let mut map = ModuleMap::new();
+    for line in f.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(prefix), Some(module), None) => map.push((prefix.into(), module.into())),
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("expected \"prefix module\", got {:?}", line)))
+            }
+        }
+    }
+    // Stable, so the first of two equal prefixes wins
+    map.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+    Ok(map)
+}
+
+/// Set the module of each symbol matching a prefix in the map, replacing the
+/// one from kallsyms
+pub fn apply_module_map(syms: &mut Syms, map: &ModuleMap) {
+    for sym in syms.values_mut() {
+        let name = &sym.name;
+        let mapped = map.iter().find(|(prefix, _)| name.starts_with(prefix.as_str()));
+        if let Some((_, module)) = mapped {
+            sym.module = Some(module.clone());
+        }
+    }
+}
+
+/// Entry points, trampolines and return hooks of ftrace itself, across the
+/// architectures' mcount and fentry schemes.
+pub const FTRACE_INTERNALS: &[&str] = &[
+    "__fentry__", "mcount", "_mcount", "__mcount", "__gnu_mcount_nc",
+    "ftrace_caller", "ftrace_regs_caller", "ftrace_call", "ftrace_regs_call",
+    "ftrace_stub", "ftrace_stub_graph", "ftrace_graph_call", "ftrace_graph_caller",
+    "ftrace_epilogue", "ftrace_trampoline", "return_to_handler",
+    "ftrace_return_to_handler", "prepare_ftrace_return", "function_trace_call",
+];
+/// Prefix of ftrace's own sections and symbols, such as the trampolines
+/// built for modules.
+pub const FTRACE_PREFIX: &str = "__ftrace";
+
+/// Whether a symbol is part of ftrace rather than the traced code
+pub fn is_ftrace_internal(sym: &Symbol) -> bool {
+    FTRACE_INTERNALS.contains(&sym.name.as_str()) || sym.name.starts_with(FTRACE_PREFIX)
+}
+
+pub struct SymOffset<'a> {
+    pub addr: u64,
+    pub offset: u64,
+    pub sym: &'a Symbol,
+    /// Address was a percpu offset rather than a kernel address
+    pub percpu: bool,
+}
+
+impl<'a> SymOffset<'a> {
+    /// Whether the offset is within the symbol's size, when it has one.
+    /// Zero-sized symbols such as section markers don't limit it.
+    fn in_range(&self) -> bool {
+        self.sym.size.is_none_or(|size| size == 0 || self.offset < size)
+    }
+}
+
+impl<'a> Display for SymOffset<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.offset > 0 {
+            write!(f, "{}+0x{:x}", self.sym, self.offset)?;
+        } else {
+            write!(f, "{}", self.sym)?;
+        }
+        if self.percpu {
+            write!(f, " (percpu)")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn find_sym(needle: u64, syms: &Syms) -> Option<SymOffset<'_>> {
+    // Most efficient way (I can find as of Rust 1.11) to search for the
+    // closest <= element. range() internally finds the first and last nodes
+    // immediately. next_back() of the DoubleEndedIterator returns the last
+    // node directly. Avoid last() on the Iterator because it uses the default
+    // implementation that iterates sequentially and takes ~10 seconds for the
+    // whole file. The find_sym_is_logarithmic test guards against that.
+    //
+    // Unbounded range on the left still traverses to the left-most node which
+    // is technically unnecessary work.
+    syms.range(..=needle).next_back()
+        .map(|(addr, sym)| SymOffset {
+            addr: *addr,
+            offset: needle - addr,
+            sym,
+            percpu: false,
+        })
+        .filter(SymOffset::in_range)
+}
+
+/// Like `find_sym`, but steps back past symbols not accepted by `accept`.
+/// Sizes aren't checked, since the offset from an accepted symbol is meant
+/// to run past it, over the skipped ones.
+pub fn find_sym_by<P: Fn(&Symbol) -> bool>(needle: u64, syms: &Syms, accept: P)
+                                           -> Option<SymOffset<'_>> {
+    // rev() walks back from the last node with next_back(), so only the
+    // skipped symbols are visited
+    syms.range(..=needle).rev()
+        .find(|&(_, sym)| accept(sym))
+        .map(|(addr, sym)| SymOffset {
+            addr: *addr,
+            offset: needle - addr,
+            sym,
+            percpu: false,
+        })
+}
+
+/// Size of the symbol at `addr`: the size from kallsyms if it has one,
+/// otherwise the distance to the next symbol. None for the last symbol.
+pub fn symbol_size(addr: u64, syms: &Syms) -> Option<u64> {
+    if let Some(size) = syms.get(&addr).and_then(|sym| sym.size).filter(|&size| size > 0) {
+        return Some(size);
+    }
+    let next = addr.checked_add(1)?;
+    syms.range(next..).next().map(|(&next, _)| next - addr)
+}
+
+/// Bounds of the percpu section as [__per_cpu_start, __per_cpu_end).
+///
+/// Percpu variables are linked as offsets from __per_cpu_start rather than at
+/// kernel addresses, so they show up in traces as small values.
+pub fn percpu_range(syms: &Syms) -> Option<(u64, u64)> {
+    let find = |name| syms.iter().find(|&(_, sym)| sym.name == name).map(|(addr, _)| *addr);
+    match (find("__per_cpu_start"), find("__per_cpu_end")) {
+        (Some(start), Some(end)) if start < end => Some((start, end)),
+        _ => None,
+    }
+}
+
+/// Address of the lowest text symbol outside modules, where the kernel image
+/// starts. Unlike the lowest symbol, often the percpu section at 0, it moves
+/// with the KASLR offset.
+pub fn text_start(syms: &Syms) -> Option<u64> {
+    syms.iter()
+        .find(|&(_, sym)| sym.sym_type.eq_ignore_ascii_case(&'t') && sym.module.is_none())
+        .map(|(&addr, _)| addr)
+}
+
+/// Resolve an address, tagging those inside the percpu section. With
+/// `exported_only`, local symbols are skipped so the offset is from the
+/// closest global symbol.
+pub fn resolve(needle: u64, syms: &Syms, percpu: Option<(u64, u64)>, exported_only: bool)
+           -> Option<SymOffset<'_>> {
+    let find = |needle| if exported_only {
+        find_sym_by(needle, syms, Symbol::is_exported)
+    } else {
+        find_sym(needle, syms)
+    };
+    match percpu {
+        // __per_cpu_start is itself a symbol, so the closest symbol below an
+        // address in the section is always one of the percpu symbols
+        Some((start, end)) if needle >= start && needle < end => {
+            find(needle).map(|sym| SymOffset { percpu: true, ..sym })
+        }
+        _ => find(needle),
+    }
+}
+
+/// Callers treated as missing by default: all ones in a 64 or 32-bit kernel
+pub const DEFAULT_NONE_ADDRS: &[u64] = &[0xffff_ffff_ffff_ffff, 0xffff_ffff];
+
+/// How `format_call` prints a call
+#[derive(Clone)]
+pub struct FormatOpts {
+    /// Printed between callee and caller. Printed literally, so "\t" is a
+    /// backslash and a t.
+    pub arrow: String,
+    /// `cpu) callee <- caller` like the kernel's function tracer, whatever
+    /// the arrow
+    pub ftrace: bool,
+    /// Resolve the callee, or print it as hex
+    pub resolve_to: bool,
+    /// Resolve the caller, or print it as hex
+    pub resolve_from: bool,
+    /// Resolve against global symbols only
+    pub exported_only: bool,
+    /// Show how far into the function offsets are
+    pub offset_detail: bool,
+    /// Cut longer symbol names to this many characters
+    pub max_name_len: Option<usize>,
+    /// Printed in place of addresses that don't resolve to a symbol
+    pub unresolved_marker: Option<String>,
+    /// Names shown for CPUs instead of their numbers
+    pub cpu_labels: HashMap<u32, String>,
+    /// Caller addresses the tracer records when there's no caller
+    pub none_addrs: Vec<u64>,
+    /// Names for addresses no symbol covers, e.g. from addr2line
+    pub fallback: BTreeMap<u64, String>,
+    /// Symbol names printed in bold inverse video to make them stand out
+    pub highlight: HashSet<String>,
+    /// Never print an address, only symbols. See `hex`.
+    pub anonymize: bool,
+    /// The percpu section from `percpu_range`, found once for the symbols
+    /// being formatted. Without it no address is tagged as percpu.
+    pub percpu: Option<(u64, u64)>,
+    /// Where the kernel text starts, from `text_start`, found once for the
+    /// symbols being formatted. `hex` looks it up itself without it.
+    pub text_start: Option<u64>,
+}
+
+impl Default for FormatOpts {
+    fn default() -> FormatOpts {
+        FormatOpts {
+            arrow: "<-".to_string(),
+            ftrace: false,
+            resolve_to: true,
+            resolve_from: true,
+            exported_only: false,
+            offset_detail: false,
+            max_name_len: None,
+            unresolved_marker: None,
+            cpu_labels: HashMap::new(),
+            none_addrs: DEFAULT_NONE_ADDRS.to_vec(),
+            fallback: BTreeMap::new(),
+            highlight: HashSet::new(),
+            anonymize: false,
+            percpu: None,
+            text_start: None,
+        }
+    }
+}
+
+impl FormatOpts {
+    /// A resolved address as `sym+0xoff`, with `offset_detail` how far into
+    /// the function that is, e.g. `vfs_read+0x30 (12% into 0x280)`
+    pub fn describe(&self, sym: &SymOffset, syms: &Syms) -> String {
+        let short = self.shorten(sym.sym);
+        let sym = &SymOffset { sym: &short, ..*sym };
+        match symbol_size(sym.addr, syms) {
+            Some(size) if self.offset_detail && sym.offset != 0 => {
+                // In u128 since gaps below the kernel text span most of u64
+                let percent = sym.offset as u128 * 100 / size as u128;
+                format!("{} ({}% into 0x{:x})", sym, percent, size)
+            }
+            _ => sym.to_string(),
+        }
+    }
+
+    /// `sym` with its name cut to `max_name_len` characters, the last being
+    /// an ellipsis
+    pub fn shorten<'a>(&self, sym: &'a Symbol) -> Cow<'a, Symbol> {
+        match self.max_name_len {
+            Some(max) if sym.name.chars().count() > max => {
+                let mut name: String = sym.name.chars().take(max.saturating_sub(1)).collect();
+                name.push('…');
+                Cow::Owned(Symbol { name, ..sym.clone() })
+            }
+            _ => Cow::Borrowed(sym),
+        }
+    }
+
+    /// How a CPU is shown, its label or number
+    pub fn cpu_label(&self, cpu: u32) -> String {
+        self.cpu_labels.get(&cpu).cloned().unwrap_or_else(|| cpu.to_string())
+    }
+
+    /// Per-CPU counts keyed by how the CPUs are shown, in CPU order. CPUs
+    /// that share a label are added together under it.
+    pub fn by_cpu_label(&self, counts: &BTreeMap<u32, usize>) -> Vec<(String, usize)> {
+        let mut labeled: Vec<(String, usize)> = Vec::new();
+        for (&cpu, &count) in counts {
+            let label = self.cpu_label(cpu);
+            match labeled.iter_mut().find(|(known, _)| *known == label) {
+                Some(entry) => entry.1 += count,
+                None => labeled.push((label, count)),
+            }
+        }
+        labeled
+    }
+
+    /// `text` in bold inverse video if `name` is one to highlight. Never in
+    /// `ftrace` lines, whose readers wouldn't expect escapes.
+    pub fn highlight(&self, name: &str, text: String) -> String {
+        if !self.ftrace && self.highlight.contains(name) {
+            format!("\x1b[1;7m{}\x1b[0m", text)
+        } else {
+            text
+        }
+    }
+
+    /// How an address that doesn't resolve to a symbol is printed
+    pub fn unresolved(&self, addr: u64) -> String {
+        self.unresolved_marker.clone().unwrap_or_else(|| format!("0x{:x}", addr))
+    }
+
+    /// `resolve` with these options. When anonymizing, an offset that would
+    /// give the address away is left unresolved: one from a symbol that
+    /// doesn't move with the KASLR offset, outside modules below the kernel
+    /// text like __per_cpu_end, or one past the kallsyms size of the symbol
+    /// the address is in.
+    pub fn resolve<'a>(&self, addr: u64, syms: &'a Syms) -> Option<SymOffset<'a>> {
+        let sym = resolve(addr, syms, self.percpu, self.exported_only)?;
+        if self.anonymize {
+            let fixed = !sym.percpu && sym.sym.module.is_none() && sym.addr < self.text_base(syms);
+            if fixed || find_sym(addr, syms).is_none() {
+                return None;
+            }
+        }
+        Some(sym)
+    }
+
+    /// `text_start`, looked up if it wasn't set
+    fn text_base(&self, syms: &Syms) -> u64 {
+        self.text_start.or_else(|| text_start(syms)).unwrap_or(0)
+    }
+
+    /// An address printed as a number: in hex, or with `anonymize` as
+    /// `<anon:...>`, a hash of how far it is from the start of the kernel
+    /// text. That distance doesn't change with the KASLR offset, so the hash
+    /// gives away nothing about where the kernel was loaded, and the same
+    /// address gets the same hash on every boot of a kernel.
+    pub fn hex(&self, addr: u64, syms: &Syms) -> String {
+        if !self.anonymize {
+            return format!("0x{:x}", addr);
+        }
+        let base = self.text_base(syms);
+        // FNV-1a
+        let hash = addr.wrapping_sub(base).to_le_bytes().iter()
+            .fold(0xcbf29ce484222325u64, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3));
+        format!("<anon:{:016x}>", hash)
+    }
+}
+
+/// A call's line, without its duration, e.g. `0 vfs_read <- ksys_read+0x6d`.
+pub fn format_call(call: &FnCall, syms: &Syms, opts: &FormatOpts) -> String {
+    // The callee is shown without its offset. Addresses left unresolved by
+    // choice are printed as hex, ones below every symbol get their fallback
+    // name or the unresolved marker.
+    let unresolved = |addr| {
+        opts.fallback.get(&addr).or(opts.unresolved_marker.as_ref()).cloned()
+            .unwrap_or_else(|| opts.hex(addr, syms))
+    };
+    let resolve = |addr| opts.resolve(addr, syms);
+    // Names from the trace that couldn't be looked up are printed as given
+    let named = |name: &str| opts.highlight(site_function(name), name.to_string());
+    let to = if let Some(ref name) = call.to_name {
+        named(site_function(name))
+    } else if opts.resolve_to {
+        resolve(call.to)
+            .map_or_else(|| unresolved(call.to),
+                         |to| opts.highlight(&to.sym.name, opts.shorten(to.sym).to_string()))
+    } else {
+        opts.hex(call.to, syms)
+    };
+    let from = if let Some(ref name) = call.from_name {
+        named(name)
+    } else if opts.none_addrs.contains(&call.from) {
+        "<unknown>".to_string()
+    } else if opts.resolve_from {
+        resolve(call.from)
+            .map_or_else(|| unresolved(call.from),
+                         |from| opts.highlight(&from.sym.name, opts.describe(&from, syms)))
+    } else {
+        opts.hex(call.from, syms)
+    };
+    if opts.ftrace {
+        format!("{:>3}) {} <- {}", opts.cpu_label(call.cpu), to, from)
+    } else {
+        format!("{} {} {} {}", opts.cpu_label(call.cpu), to, opts.arrow, from)
+    }
+}
+
+pub struct FnCall {
+    pub cpu: u32,
+    pub from: u64,
+    pub to: u64,
+    /// Time spent in the call in microseconds, if the trace records it
+    pub duration: Option<f64>,
+    /// When the call was made, for kernels that prefix records with
+    /// `CPU:N ts:N`
+    pub ts: Option<u64>,
+    /// The callee was named in the trace, so `to` was looked up in the
+    /// kallsyms and is already relocated
+    pub to_named: bool,
+    /// The same for the caller and `from`
+    pub from_named: bool,
+    /// The callee as the trace named it, e.g. `show+0x40`, when that couldn't
+    /// be looked up because the kallsyms has no symbol or several by that
+    /// name. `to` is 0 then and the name is printed as given.
+    pub to_name: Option<String>,
+    /// The same for the caller
+    pub from_name: Option<String>,
+}
+
+impl FnCall {
+    /// What calls are grouped under in reports: `function_key` of the
+    /// callee, or its name from the trace if it couldn't be looked up
+    fn callee_key(&self, syms: &Syms, granularity: Option<u64>) -> String {
+        site_key(self.to, self.to_name.as_ref(), syms, granularity)
+    }
+
+    /// The same for the caller
+    fn caller_key(&self, syms: &Syms, granularity: Option<u64>) -> String {
+        site_key(self.from, self.from_name.as_ref(), syms, granularity)
+    }
+}
+
+fn site_key(addr: u64, name: Option<&String>, syms: &Syms, granularity: Option<u64>) -> String {
+    match name {
+        Some(name) => site_function(name).to_string(),
+        None => function_key(addr, syms, granularity),
+    }
+}
+
+/// The function in a debugfs trace site, `name` for `name+0x40`
+pub fn site_function(site: &str) -> &str {
+    site.split("+0x").next().unwrap()
+}
+
+/// Parse a trace. Lines that aren't calls are skipped with a warning.
+pub fn ftrace<F: BufRead>(f: F, opts: &ParseOpts) -> Vec<FnCall> {
+    ftrace_with_stats(f, opts).0
+}
+
+/// Like `ftrace`, also counting the lines read
+pub fn ftrace_with_stats<F: BufRead>(f: F, opts: &ParseOpts) -> (Vec<FnCall>, ParseStats) {
+    // The addresses are captured loosely and checked afterwards, so stray
+    // function_graph `=>` or `|` characters are reported rather than
+    // silently matching somewhere else in the line
+    let cpu = if opts.cpu_hex { "[0-9a-fA-F]+" } else { r"\d+" };
+    let regex = format!(r"(?x)
+        (?:CPU:(?P<tcpu>{cpu})\s+ts:(?P<ts>\d+)  # CPU and timestamp
+        |(?:CPU\#?)?(?P<cpu>{cpu}):?)\s+        # CPU, maybe as CPU3: or CPU#3
+        (?P<to>\S+)\s+             # To Addr
+        (?P<from>\S+)\s+           # From Addr
+        (?:.*?\b(?P<dur>\d+\.\d+)\s*us\b)? # Optional function_graph duration
+        ", cpu = cpu);
+    let regex = Regex::new(&regex).unwrap();
+    let radix = if opts.cpu_hex { 16 } else { 10 };
+    let mut calls = Vec::new();
+    let mut errors = 0;
+    let mut lines = bounded_lines(f, opts);
+    for line in &mut lines {
+        let call = match regex.captures(&line) {
+            Some(caps) => parse_call(&caps, radix),
+            None => Err("no call found".to_string()),
+        };
+        match call {
+            Ok(call) => calls.push(call),
+            Err(e) => {
+                eprintln!("Warning: skipping ftrace line, {}: {}", e, line);
+                errors += 1;
+            }
+        }
+    }
+    let stats = ParseStats::from_lines(&lines, calls.len(), errors);
+    (calls, stats)
+}
+
+fn parse_call(caps: &Captures, cpu_radix: u32) -> Result<FnCall, String> {
+    let field = |name, what| {
+        let value = caps.name(name).unwrap();
+        u64::from_str_radix(value, 16)
+            .map_err(|_| format!("{} address \"{}\" isn't hex", what, value))
+    };
+    let to = field("to", "callee")?;
+    let from = field("from", "caller")?;
+    let cpu = caps.name("cpu").or_else(|| caps.name("tcpu")).unwrap();
+    let cpu = u32::from_str_radix(cpu, cpu_radix)
+        .map_err(|_| format!("CPU \"{}\" is out of range", cpu))?;
+    let ts = match caps.name("ts") {
+        Some(ts) => Some(ts.parse().map_err(|_| format!("timestamp \"{}\" is out of range", ts))?),
+        None => None,
+    };
+    let duration = caps.name("dur").and_then(|d| d.parse().ok());
+    Ok(FnCall {
+        cpu,
+        from,
+        to,
+        duration,
+        ts,
+        to_named: false,
+        from_named: false,
+        to_name: None,
+        from_name: None,
+    })
+}
+
+/// Function names and their addresses, to turn the names in a debugfs trace
+/// back into addresses
+pub type SymbolNames = HashMap<String, u64>;
+
+/// The names of `syms`. Names that repeat, like static functions in
+/// different files, are left out, as a trace naming one doesn't say which.
+pub fn symbol_names(syms: &Syms) -> SymbolNames {
+    let mut names = HashMap::new();
+    let mut repeated = HashSet::new();
+    for (&addr, sym) in syms {
+        if names.insert(sym.name.clone(), addr).is_some() {
+            repeated.insert(sym.name.clone());
+        }
+    }
+    names.retain(|name, _| !repeated.contains(name));
+    names
+}
+
+/// Parse the function tracer's live debugfs `trace` file, e.g.
+/// `bash-1234 [002] d..1 1234.567890: vfs_read <-ksys_read+0x6d`. Timestamps
+/// are kept in microseconds. Functions shown by name are looked up in `names`
+/// to get their address back, so they resolve to the same name and the
+/// features that work on addresses see them. Names `names` doesn't have, or
+/// that are ambiguous, are kept as the trace gives them, as are all of them
+/// without `names`, e.g. to only count the calls. Comment lines are skipped
+/// silently.
+pub fn debugfs_with_stats<F: BufRead>(f: F, opts: &ParseOpts, names: Option<&SymbolNames>)
+                                      -> (Vec<FnCall>, ParseStats) {
+    let regex = Regex::new(r"(?x)
+        ^\s*.+?-\d+\s+                         # Task and PID
+        (?:\(\s*[^)]*\)\s+)?                   # Optional TGID
+        \[(?P<cpu>\d+)\]\s+                    # CPU
+        (?:[^\s:]+\s+)?                        # Optional irq flags
+        (?P<secs>\d+)\.(?P<frac>\d+):\s+        # Timestamp
+        (?P<to>\S+)(?:\s+<(?P<toaddr>[0-9a-f]+)>)?(?:\s+\[[^\]]+\])?\s+
+        <-(?P<from>\S+)(?:\s+<(?P<fromaddr>[0-9a-f]+)>)?
+        ").unwrap();
+    let mut calls = Vec::new();
+    let mut errors = 0;
+    let mut lines = bounded_lines(f, opts);
+    for line in &mut lines {
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        let call = match regex.captures(&line) {
+            Some(caps) => parse_debugfs_call(&caps, names),
+            None => Err("no call found".to_string()),
+        };
+        match call {
+            Ok(call) => calls.push(call),
+            Err(e) => {
+                eprintln!("Warning: skipping ftrace line, {}: {}", e, line);
+                errors += 1;
+            }
+        }
+    }
+    let stats = ParseStats::from_lines(&lines, calls.len(), errors);
+    (calls, stats)
+}
+
+fn parse_debugfs_call(caps: &Captures, names: Option<&SymbolNames>)
+                      -> Result<FnCall, String> {
+    let site = |name, addr| match caps.name(addr) {
+        // With the sym-addr trace option the address follows the name
+        Some(addr) => u64::from_str_radix(addr, 16).map(|addr| (addr, false, None))
+            .map_err(|_| format!("address \"{}\" is out of range", addr)),
+        None => debugfs_site(caps.name(name).unwrap(), names),
+    };
+    let (to, to_named, to_name) = site("to", "toaddr")?;
+    let (from, from_named, from_name) = site("from", "fromaddr")?;
+    let cpu = caps.name("cpu").unwrap();
+    let cpu = cpu.parse().map_err(|_| format!("CPU \"{}\" is out of range", cpu))?;
+    let secs: u64 = caps.name("secs").unwrap().parse()
+        .map_err(|_| "timestamp is out of range".to_string())?;
+    // Pad or cut the fraction to microseconds
+    let frac: String = caps.name("frac").unwrap().chars().chain("000000".chars()).take(6).collect();
+    let ts = secs.checked_mul(1_000_000).and_then(|us| us.checked_add(frac.parse().unwrap()))
+        .ok_or_else(|| "timestamp is out of range".to_string())?;
+    Ok(FnCall {
+        cpu,
+        from,
+        to,
+        duration: None,
+        ts: Some(ts),
+        to_named,
+        from_named,
+        to_name,
+        from_name,
+    })
+}
+
+/// The address of a debugfs trace function: hex when the tracer couldn't
+/// name it, otherwise `name`, `name+0xoff` or, with the sym-offset trace
+/// option, `name+0xoff/0xsize`. Also whether it was named, and the name as
+/// given if it isn't in `names`.
+fn debugfs_site(site: &str, names: Option<&SymbolNames>)
+                -> Result<(u64, bool, Option<String>), String> {
+    if let Some(hex) = site.strip_prefix("0x") {
+        return u64::from_str_radix(hex, 16).map(|addr| (addr, false, None))
+            .map_err(|_| format!("address \"{}\" isn't hex", site));
+    }
+    let site = site.split('/').next().unwrap();
+    let mut parts = site.splitn(2, "+0x");
+    let name = parts.next().unwrap();
+    let offset = match parts.next() {
+        Some(offset) => u64::from_str_radix(offset, 16)
+            .map_err(|_| format!("offset in \"{}\" isn't hex", site))?,
+        None => 0,
+    };
+    match names.and_then(|names| names.get(name)) {
+        Some(addr) => Ok((addr + offset, true, None)),
+        None => Ok((0, true, Some(site.to_string()))),
+    }
+}
+
+/// Count the calls with an earlier timestamp than the call before them on
+/// the same CPU, e.g. after a clock adjustment or ring buffer wrap
+pub fn timestamp_inversions(calls: &[FnCall]) -> usize {
+    let mut last: HashMap<u32, u64> = HashMap::new();
+    calls.iter().filter(|call| match call.ts {
+        Some(ts) => last.insert(call.cpu, ts).is_some_and(|prev| ts < prev),
+        None => false,
+    }).count()
+}
+
+/// Merge traces into one ordered by timestamp, keeping the order within each
+/// trace and breaking ties by trace order. Calls without a timestamp sort
+/// before all others.
+pub fn merge_by_timestamp(traces: Vec<Vec<FnCall>>) -> Vec<FnCall> {
+    let mut traces: Vec<_> = traces.into_iter().map(|calls| calls.into_iter().peekable())
+        .collect();
+    // Min-heap of the next timestamp of each trace
+    let mut next = BinaryHeap::new();
+    for (i, trace) in traces.iter_mut().enumerate() {
+        if let Some(call) = trace.peek() {
+            next.push(Reverse((call.ts, i)));
+        }
+    }
+    let mut merged = Vec::new();
+    while let Some(Reverse((_, i))) = next.pop() {
+        merged.push(traces[i].next().unwrap());
+        if let Some(call) = traces[i].peek() {
+            next.push(Reverse((call.ts, i)));
+        }
+    }
+    merged
+}
+
+/// Reconstruct the call stacks, giving the index of the call each call was
+/// made from.
+///
+/// The trace only records function entries, so the stacks are inferred per
+/// CPU: a call is nested in the innermost open call whose function contains
+/// its caller address, and everything opened above that has returned. A call
+/// whose caller isn't open at all starts a new stack, which happens when the
+/// trace begins partway down a call chain.
+pub fn call_parents(calls: &[FnCall], syms: &Syms) -> Vec<Option<usize>> {
+    call_parents_with_underflows(calls, syms).0
+}
+
+/// A function open on a reconstructed stack: where it starts, or its name
+/// from the trace if that couldn't be looked up
+type StackFunction<'a> = Result<u64, &'a str>;
+
+/// Like `call_parents`, also counting the stack underflows on each CPU: calls
+/// after the first whose caller isn't open, meaning the trace returned above
+/// where it started. The stack restarts empty at each one.
+pub fn call_parents_with_underflows<'a>(calls: &'a [FnCall], syms: &Syms)
+                                    -> (Vec<Option<usize>>, BTreeMap<u32, usize>) {
+    // Functions a debugfs trace names that couldn't be looked up are matched
+    // by name
+    let func = |addr, name: Option<&'a String>| match name {
+        Some(name) => Err(site_function(name)),
+        None => Ok(find_sym(addr, syms).map_or(addr, |sym| sym.addr)),
+    };
+    let mut stacks: HashMap<u32, Vec<(usize, StackFunction)>> = HashMap::new();
+    let mut underflows = BTreeMap::new();
+    let parents = calls.iter().enumerate().map(|(i, call)| {
+        let first = !stacks.contains_key(&call.cpu);
+        let stack = stacks.entry(call.cpu).or_default();
+        let caller = func(call.from, call.from_name.as_ref());
+        match stack.iter().rposition(|&(_, f)| f == caller) {
+            Some(pos) => stack.truncate(pos + 1),
+            None => {
+                stack.clear();
+                if !first {
+                    *underflows.entry(call.cpu).or_insert(0) += 1;
+                }
+            }
+        }
+        let parent = stack.last().map(|&(parent, _)| parent);
+        stack.push((i, func(call.to, call.to_name.as_ref())));
+        parent
+    }).collect();
+    (parents, underflows)
+}
+
+/// Name of the function containing an address, without the offset, as used
+/// to group calls in reports. Unresolved addresses are named in hex.
+pub fn function_name(addr: u64, syms: &Syms) -> String {
+    find_sym(addr, syms).map_or_else(|| format!("0x{:x}", addr), |sym| sym.sym.to_string())
+}
+
+/// The name calls into `addr` are grouped under in reports. Without a
+/// granularity that's the function name, with one the offset rounded down to
+/// a multiple of it is kept, e.g. `foo+0x40` for `foo+0x64` and 0x40.
+pub fn function_key(addr: u64, syms: &Syms, granularity: Option<u64>) -> String {
+    match (find_sym(addr, syms), granularity) {
+        (Some(sym), Some(granularity)) => {
+            let offset = sym.offset - sym.offset % granularity;
+            if offset > 0 {
+                format!("{}+0x{:x}", sym.sym, offset)
+            } else {
+                sym.sym.to_string()
+            }
+        }
+        _ => function_name(addr, syms),
+    }
+}
+
+/// Time attributed to one function, in microseconds
+pub struct SymbolTime {
+    pub name: String,
+    pub calls: usize,
+    /// Time in the function including the calls it made
+    pub inclusive: f64,
+    /// Time in the function itself
+    pub exclusive: f64,
+}
+
+/// Sum call durations per callee, keyed as by `function_key` and sorted by
+/// inclusive time. Calls without a recorded duration don't count.
+pub fn time_by_symbol(calls: &[FnCall], syms: &Syms, granularity: Option<u64>)
+                      -> Vec<SymbolTime> {
+    let mut child_time = vec![0.0; calls.len()];
+    for (call, parent) in calls.iter().zip(call_parents(calls, syms)) {
+        if let (Some(duration), Some(parent)) = (call.duration, parent) {
+            child_time[parent] += duration;
+        }
+    }
+
+    let mut times: BTreeMap<String, SymbolTime> = BTreeMap::new();
+    for (call, child_time) in calls.iter().zip(child_time) {
+        if let Some(duration) = call.duration {
+            let name = call.callee_key(syms, granularity);
+            let time = times.entry(name.clone()).or_insert_with(|| SymbolTime {
+                name,
+                calls: 0,
+                inclusive: 0.0,
+                exclusive: 0.0,
+            });
+            time.calls += 1;
+            time.inclusive += duration;
+            time.exclusive += (duration - child_time).max(0.0);
+        }
+    }
+    // Stable sort keeps equal times in name order
+    let mut times: Vec<SymbolTime> = times.into_values().collect();
+    times.sort_by(|a, b| b.inclusive.partial_cmp(&a.inclusive).unwrap());
+    times
+}
+
+/// Count occurrences of each name and return the `n` most common, most
+/// common first and ties in name order.
+pub fn top_counts<I: Iterator<Item = String>>(names: I, n: usize) -> Vec<(String, usize)> {
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for name in names {
+        *counts.entry(name).or_insert(0) += 1;
+    }
+    // Stable sort keeps equal counts in name order
+    let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+    counts.sort_by_key(|&(_, count)| Reverse(count));
+    counts.truncate(n);
+    counts
+}
+
+/// The `n` most called functions, keyed as by `function_key`
+pub fn top_callees(calls: &[FnCall], syms: &Syms, n: usize, granularity: Option<u64>)
+                   -> Vec<(String, usize)> {
+    top_counts(calls.iter().map(|call| call.callee_key(syms, granularity)), n)
+}
+
+/// The `n` functions making the most calls, keyed as by `function_key`
+pub fn top_callers(calls: &[FnCall], syms: &Syms, n: usize, granularity: Option<u64>)
+                   -> Vec<(String, usize)> {
+    top_counts(calls.iter().map(|call| call.caller_key(syms, granularity)), n)
+}
+
+/// Every call's reconstructed stack in the folded format of FlameGraph's
+/// stackcollapse scripts, `caller;callee;...;call`, with how many calls had
+/// that stack. Each call is one sample, wherever it sits in the tree. The
+/// outermost frame is the caller of the call at the bottom of the stack.
+pub fn folded_samples(calls: &[FnCall], syms: &Syms) -> BTreeMap<String, usize> {
+    let parents = call_parents(calls, syms);
+    let mut samples = BTreeMap::new();
+    for i in 0..calls.len() {
+        let mut frames = vec![calls[i].callee_key(syms, None)];
+        let mut root = i;
+        while let Some(parent) = parents[root] {
+            frames.push(calls[parent].callee_key(syms, None));
+            root = parent;
+        }
+        frames.push(calls[root].caller_key(syms, None));
+        frames.reverse();
+        *samples.entry(frames.join(";")).or_insert(0) += 1;
+    }
+    samples
+}
+
+/// A call in the reconstructed call tree
+pub struct CallNode {
+    /// Callee name, or hex if it didn't resolve
+    pub symbol: String,
+    /// Callee address
+    pub addr: u64,
+    pub cpu: u32,
+    /// Nesting depth, 0 for the first call of each tree
+    pub depth: usize,
+    /// Calls made from this one, in trace order
+    pub children: Vec<CallNode>,
+    /// Calls this node stands for, more than 1 once `fold_recursion` has
+    /// merged recursive calls into it
+    pub calls: usize,
+}
+
+/// Reconstruct the call trees of each CPU, with nesting inferred as in
+/// `call_parents`. The roots of all CPUs are returned in trace order.
+pub fn build_call_tree(calls: &[FnCall], syms: &Syms) -> Vec<CallNode> {
+    let parents = call_parents(calls, syms);
+    let mut depths: Vec<usize> = Vec::with_capacity(calls.len());
+    for parent in &parents {
+        let depth = parent.map_or(0, |parent| depths[parent] + 1);
+        depths.push(depth);
+    }
+    let mut nodes: Vec<Option<CallNode>> = calls.iter().zip(depths).map(|(call, depth)| {
+        Some(CallNode {
+            symbol: call.callee_key(syms, None),
+            addr: call.to,
+            cpu: call.cpu,
+            depth,
+            children: Vec::new(),
+            calls: 1,
+        })
+    }).collect();
+
+    // Parents always precede their children, so walking backwards attaches
+    // every node's children before the node itself is moved into its parent.
+    // The children arrive last first.
+    let mut roots = Vec::new();
+    for (i, parent) in parents.into_iter().enumerate().rev() {
+        let mut node = nodes[i].take().unwrap();
+        node.children.reverse();
+        match parent {
+            Some(parent) => nodes[parent].as_mut().unwrap().children.push(node),
+            None => roots.push(node),
+        }
+    }
+    roots.reverse();
+    roots
+}
+
+/// Collapse direct recursion in call trees. A call made from the function
+/// it calls is merged into its caller: its own calls take its place among
+/// the caller's, and it's counted in the caller's `calls`. A chain of 12
+/// calls to `foo` becomes one `foo` node with `calls` 12.
+pub fn fold_recursion(roots: &mut [CallNode]) {
+    // Walk with an explicit stack since traces can nest deeply
+    let mut stack: Vec<&mut CallNode> = roots.iter_mut().collect();
+    while let Some(node) = stack.pop() {
+        let mut pending = std::mem::take(&mut node.children);
+        pending.reverse();
+        while let Some(child) = pending.pop() {
+            if child.symbol == node.symbol {
+                node.calls += child.calls;
+                pending.extend(child.children.into_iter().rev());
+            } else {
+                node.children.push(child);
+            }
+        }
+        for child in &mut node.children {
+            child.depth = node.depth + 1;
+        }
+        stack.extend(node.children.iter_mut());
+    }
+}
+
+/// Address width of the kernel in bits, 32 or 64, judged by the most
+/// significant bit set in the highest symbol address. Unlike counting hex
+/// digits this doesn't depend on how kallsyms padded the addresses.
+pub fn detect_address_width(syms: &Syms) -> u32 {
+    let max = syms.keys().next_back().cloned().unwrap_or(0);
+    if 64 - max.leading_zeros() > 32 { 64 } else { 32 }
+}
+
+/// Reverse the byte order of an address of the given width in bits.
+pub fn byteswap(addr: u64, width: u32) -> u64 {
+    if width == 32 {
+        (addr as u32).swap_bytes() as u64
+    } else {
+        addr.swap_bytes()
+    }
+}
+
+/// Identifies a symbol index file written by --build-index
+pub const INDEX_MAGIC: &[u8; 8] = b"FRFMTIDX";
+/// Bump whenever the layout written by `write_index` changes
+pub const INDEX_VERSION: u32 = 3;
+
+fn write_str<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    w.write_all(&(s.len() as u32).to_le_bytes())?;
+    w.write_all(s.as_bytes())
+}
+
+/// Serialize symbols so later runs can skip parsing the text kallsyms.
+///
+/// The layout is the magic and version, the symbol count, then for each
+/// symbol in address order: the address, the type letter as one byte, the
+/// name, a module flag byte followed by the module name if the flag is set,
+/// and a size flag byte followed by the size as a u64 if it is set. Integers
+/// are little endian; strings are a u32 length followed by UTF-8 bytes.
+pub fn write_index<W: Write>(mut w: W, syms: &Syms) -> io::Result<()> {
+    w.write_all(INDEX_MAGIC)?;
+    w.write_all(&INDEX_VERSION.to_le_bytes())?;
+    w.write_all(&(syms.len() as u64).to_le_bytes())?;
+    for (addr, sym) in syms {
+        w.write_all(&addr.to_le_bytes())?;
+        w.write_all(&[sym.sym_type as u8])?;
+        write_str(&mut w, &sym.name)?;
+        match sym.module {
+            Some(ref module) => {
+                w.write_all(&[1])?;
+                write_str(&mut w, module)?;
+            }
+            None => w.write_all(&[0])?,
+        }
+        match sym.size {
+            Some(size) => {
+                w.write_all(&[1])?;
+                w.write_all(&size.to_le_bytes())?;
+            }
+            None => w.write_all(&[0])?,
+        }
+    }
+    w.flush()
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_str<R: Read>(r: &mut R) -> io::Result<String> {
+    // Read through take() rather than allocating the length up front, so a
+    // corrupt length fails at the end of the file instead of asking for GiBs
+    let len = read_u32(r)? as u64;
+    let mut buf = Vec::new();
+    r.take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated string in index"));
+    }
+    String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Load symbols written by `write_index`, rejecting other files and indices
+/// from other versions of the format.
+pub fn read_index<R: Read>(mut r: R) -> io::Result<Syms> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != INDEX_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a symbol index"));
+    }
+    let version = read_u32(&mut r)?;
+    if version != INDEX_VERSION {
+        let msg = format!("index version {} is not supported (expected {}), \
+                           rebuild it with --build-index", version, INDEX_VERSION);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    let mut syms = Syms::new();
+    for _ in 0..read_u64(&mut r)? {
+        let addr = read_u64(&mut r)?;
+        let mut sym_type = [0];
+        r.read_exact(&mut sym_type)?;
+        let name = read_str(&mut r)?;
+        let mut flag = [0];
+        r.read_exact(&mut flag)?;
+        let module = if flag[0] != 0 { Some(read_str(&mut r)?) } else { None };
+        r.read_exact(&mut flag)?;
+        let size = if flag[0] != 0 { Some(read_u64(&mut r)?) } else { None };
+        syms.insert(addr, Symbol {
+            name,
+            module,
+            sym_type: sym_type[0] as char,
+            size,
+        });
+    }
+    Ok(syms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn syms_at(addrs: &[u64]) -> Syms {
+        addrs.iter().map(|&addr| {
+            (addr, Symbol { name: format!("s{:x}", addr), module: None, sym_type: 'T', size: None })
+        }).collect()
+    }
+
+    #[test]
+    fn find_sym_matches_linear_scan() {
+        // The closest symbol at or below each needle, found the slow way by
+        // visiting every symbol
+        let syms = syms_at(&[0x10, 0x20, 0x21, 0x80, 0xffffffff81000000]);
+        for needle in (0..0x100).chain(0xffffffff80ffff00..0xffffffff81000100) {
+            let expected = syms.iter().take_while(|&(&addr, _)| addr <= needle)
+                .fold(None, |_, sym| Some(sym));
+            let found = find_sym(needle, &syms);
+            assert_eq!(found.as_ref().map(|s| (s.addr, s.offset)),
+                       expected.map(|(&addr, _)| (addr, needle - addr)));
+            if let (Some(found), Some((_, sym))) = (found, expected) {
+                assert_eq!(found.sym.name, sym.name);
+            }
+        }
+    }
+
+    #[test]
+    fn find_sym_is_logarithmic() {
+        // A kernel-sized symbol table and a trace's worth of lookups. Each
+        // lookup has to go straight to its node; one that walks the symbols
+        // sequentially, as last() on the range used to, takes minutes here.
+        // The limit is generous enough for a slow debug build, and the loop
+        // gives up as soon as it's passed rather than finishing the scan.
+        let addrs: Vec<u64> = (0..200_000).map(|i| 0xffffffff81000000 + i * 0x40).collect();
+        let syms = syms_at(&addrs);
+        let limit = std::time::Duration::from_secs(5);
+        let start = std::time::Instant::now();
+        for i in 0..100_000u64 {
+            let needle = 0xffffffff81000000 + (i * 7919 % 200_000) * 0x40 + 0x10;
+            assert_eq!(find_sym(needle, &syms).map(|s| s.offset), Some(0x10));
+            assert!(start.elapsed() < limit,
+                    "{} lookups took over {:?}; is find_sym scanning linearly?", i + 1, limit);
+        }
+    }
+
+    /// (symbol name, offset) found for a needle
+    fn found(needle: u64, syms: &Syms) -> Option<(String, u64)> {
+        find_sym(needle, syms).map(|s| (s.sym.name.clone(), s.offset))
+    }
+
+    #[test]
+    fn find_sym_exact() {
+        let syms = syms_at(&[0x1000, 0x2000]);
+        assert_eq!(found(0x1000, &syms), Some(("s1000".to_string(), 0)));
+        assert_eq!(found(0x2000, &syms), Some(("s2000".to_string(), 0)));
+        // No offset is printed at the start of a symbol
+        assert_eq!(find_sym(0x2000, &syms).unwrap().to_string(), "s2000");
+    }
+
+    #[test]
+    fn find_sym_below_lowest() {
+        let syms = syms_at(&[0x1000, 0x2000]);
+        assert_eq!(found(0xfff, &syms), None);
+        assert_eq!(found(0, &syms), None);
+        assert_eq!(found(0x1000, &Syms::new()), None);
+    }
+
+    #[test]
+    fn find_sym_above_highest() {
+        // Without sizes the highest symbol extends to the end of the space
+        let syms = syms_at(&[0x1000, 0x2000]);
+        assert_eq!(found(0x2001, &syms), Some(("s2000".to_string(), 1)));
+        assert_eq!(found(u64::MAX, &syms), Some(("s2000".to_string(), u64::MAX - 0x2000)));
+    }
+
+    #[test]
+    fn find_sym_sized() {
+        let text = "1000 10 T sized\n2000 0 t marker\n3000 t unsized\n";
+        let syms = kallsyms(text.as_bytes(), &ParseOpts::default());
+        assert_eq!(syms[&0x1000].size, Some(0x10));
+        assert_eq!(syms[&0x3000].size, None);
+        assert_eq!(found(0x100f, &syms), Some(("sized".to_string(), 0xf)));
+        // Past the end of a sized symbol is in no symbol
+        assert_eq!(found(0x1010, &syms), None);
+        assert_eq!(found(0x2fff, &syms), Some(("marker".to_string(), 0xfff)));
+        assert_eq!(symbol_size(0x1000, &syms), Some(0x10));
+        assert_eq!(symbol_size(0x2000, &syms), Some(0x1000));
+
+        let (lazy, _) = kallsyms_near(text.as_bytes(), &ParseOpts::default(), &[0x1004], false);
+        assert_eq!(lazy.keys().cloned().collect::<Vec<_>>(), vec![0x1000, 0x2000]);
+    }
+
+    #[test]
+    fn exported_only() {
+        // The local symbol in between is skipped, so the offset is from the
+        // global one below it
+        let text = "1000 T global_fn\n1800 t static_fn\n2000 T next_fn\n";
+        let syms = kallsyms(text.as_bytes(), &ParseOpts::default());
+        assert_eq!(resolve(0x1810, &syms, None, true).unwrap().to_string(), "global_fn+0x810");
+        assert_eq!(resolve(0x1810, &syms, None, false).unwrap().to_string(), "static_fn+0x10");
+        assert_eq!(resolve(0x2000, &syms, None, true).unwrap().to_string(), "next_fn");
+        // Below every global symbol nothing resolves
+        let syms = kallsyms(&b"1000 t static_fn\n"[..], &ParseOpts::default());
+        assert!(resolve(0x1010, &syms, None, true).is_none());
+    }
+
+    #[test]
+    fn find_sym_by_sized() {
+        // An offset from the global symbol runs past its size, over the
+        // local one
+        let text = "100 40 T global_fn\n200 80 t static_fn\n";
+        let syms = kallsyms(text.as_bytes(), &ParseOpts::default());
+        let sym = find_sym_by(0x210, &syms, Symbol::is_exported).unwrap();
+        assert_eq!(sym.to_string(), "global_fn+0x110");
+        assert_eq!(resolve(0x210, &syms, None, true).unwrap().to_string(), "global_fn+0x110");
+        assert_eq!(found(0x210, &syms), Some(("static_fn".to_string(), 0x10)));
+    }
+
+    #[test]
+    fn percpu_addresses() {
+        let text = "0 A __per_cpu_start\n10 A cpu_number\n40 A __per_cpu_end\n\
+                    ffffffff81000000 T _stext\n";
+        let syms = kallsyms(text.as_bytes(), &ParseOpts::default());
+        let percpu = percpu_range(&syms);
+        assert_eq!(percpu, Some((0, 0x40)));
+        let resolved = |addr| resolve(addr, &syms, percpu, false).unwrap().to_string();
+        assert_eq!(resolved(0), "__per_cpu_start (percpu)");
+        assert_eq!(resolved(0x14), "cpu_number+0x4 (percpu)");
+        assert_eq!(resolved(0x3f), "cpu_number+0x2f (percpu)");
+        // The end is just past the section
+        assert_eq!(resolved(0x40), "__per_cpu_end");
+        assert_eq!(resolved(0xffffffff81000004), "_stext+0x4");
+        // Without the range nothing is tagged
+        assert_eq!(resolve(0x14, &syms, None, false).unwrap().to_string(), "cpu_number+0x4");
+        assert_eq!(percpu_range(&syms_at(&[0x1000])), None);
+    }
+
+    #[test]
+    fn find_sym_between() {
+        let syms = syms_at(&[0x1000, 0x2000, 0x2001]);
+        assert_eq!(found(0x1001, &syms), Some(("s1000".to_string(), 1)));
+        assert_eq!(found(0x1fff, &syms), Some(("s1000".to_string(), 0xfff)));
+        assert_eq!(find_sym(0x1abc, &syms).unwrap().to_string(), "s1000+0xabc");
+        // Adjacent symbols leave no gap between them
+        assert_eq!(found(0x2001, &syms), Some(("s2001".to_string(), 0)));
+    }
+
+    #[test]
+    fn format_calls() {
+        let syms = syms_at(&[0x1000, 0x2000]);
+        let opts = FormatOpts::default();
+        let line = |call, opts| format_call(&call, &syms, opts);
+        assert_eq!(line(call(1, 0x2000, 0x1004), &opts), "1 s2000 <- s1000+0x4");
+        assert_eq!(line(call(0, 0x2000, !0), &opts), "0 s2000 <- <unknown>");
+        let opts = FormatOpts { ftrace: true, resolve_from: false, ..FormatOpts::default() };
+        assert_eq!(line(call(1, 0x2000, 0x1004), &opts), "  1) s2000 <- 0x1004");
+        let opts = FormatOpts {
+            unresolved_marker: Some("?".to_string()),
+            cpu_labels: vec![(0, "little".to_string())].into_iter().collect(),
+            ..FormatOpts::default()
+        };
+        assert_eq!(line(call(0, 0x2000, 0x10), &opts), "little s2000 <- ?");
+        let opts = FormatOpts {
+            highlight: vec!["s1000".to_string()].into_iter().collect(),
+            ..FormatOpts::default()
+        };
+        assert_eq!(line(call(1, 0x2000, 0x1004), &opts), "1 s2000 <- \x1b[1;7ms1000+0x4\x1b[0m");
+        let opts = FormatOpts { ftrace: true, ..opts.clone() };
+        assert_eq!(line(call(1, 0x2000, 0x1004), &opts), "  1) s2000 <- s1000+0x4");
+    }
+
+    #[test]
+    fn cpu_labels() {
+        let cpu_labels = (0..4).map(|cpu| (cpu, "little".to_string())).collect();
+        let opts = FormatOpts { cpu_labels, ..FormatOpts::default() };
+        let counts: BTreeMap<u32, usize> = vec![(0, 2), (3, 1), (4, 5), (10, 1)].into_iter()
+            .collect();
+        assert_eq!(opts.by_cpu_label(&counts), vec![("little".to_string(), 3),
+                                                    ("4".to_string(), 5), ("10".to_string(), 1)]);
+    }
+
+    #[test]
+    fn anonymized_addresses() {
+        // The percpu section sits at 0 whatever the KASLR offset, so it's
+        // the kernel text the hash is relative to
+        let kernel = |text: u64| -> Syms {
+            let mut syms = syms_at(&[text, text + 0x1000]);
+            syms.insert(0, Symbol {
+                name: "__per_cpu_start".to_string(),
+                module: None,
+                sym_type: 'A',
+                size: None,
+            });
+            syms
+        };
+        let opts = FormatOpts { anonymize: true, resolve_to: false, ..FormatOpts::default() };
+        let syms = kernel(0xffffffff81000000);
+        assert_eq!(text_start(&syms), Some(0xffffffff81000000));
+        let line = |syms: &Syms, to, from| format_call(&call(0, to, from), syms, &opts);
+        let anon = line(&syms, 0xffffffff81001000, 0xffffffff80000010);
+        assert!(!anon.contains("0x"), "{}", anon);
+        let moved = kernel(0xffffffff9a000000);
+        assert_eq!(line(&moved, 0xffffffff9a001000, 0xffffffff99000010), anon);
+        assert_ne!(line(&syms, 0xffffffff81001000, 0xffffffff80000020), anon);
+    }
+
+    #[test]
+    fn anonymized_modules() {
+        // Modules load below the kernel text on 32-bit ARM and arm64, and
+        // still resolve, unless past the symbol's size
+        let text = "bf000100 40 t mydrv_irq\t[mydrv]\nc0008000 T _stext\n";
+        let syms = kallsyms(text.as_bytes(), &ParseOpts::default());
+        let opts = FormatOpts { anonymize: true, ..FormatOpts::default() };
+        let line = |to, from| format_call(&call(0, to, from), &syms, &opts);
+        assert_eq!(line(0xc0008000, 0xbf000110), "0 _stext <- mydrv_irq[mydrv]+0x10");
+        assert!(line(0xc0008000, 0xbf000140).contains("<anon:"));
+        assert!(opts.resolve(0xbf000140, &syms).is_none());
+    }
+
+    #[test]
+    fn offset_detail() {
+        let syms = syms_at(&[0, 0x1000, 0xffffffff81000000]);
+        let opts = FormatOpts { offset_detail: true, ..FormatOpts::default() };
+        let describe = |addr| opts.describe(&find_sym(addr, &syms).unwrap(), &syms);
+        assert_eq!(describe(0x1040), "s1000+0x40 (0% into 0xffffffff80fff000)");
+        assert_eq!(describe(0xffffffff80000000),
+                   "s1000+0xffffffff7ffff000 (99% into 0xffffffff80fff000)");
+        let syms = syms_at(&[0x1000, 0x1100]);
+        let describe = |addr| opts.describe(&find_sym(addr, &syms).unwrap(), &syms);
+        assert_eq!(describe(0x1080), "s1000+0x80 (50% into 0x100)");
+        assert_eq!(describe(0x1000), "s1000");
+    }
+
+    #[test]
+    fn offset_granularity() {
+        let syms = syms_at(&[0x1000, 0x2000]);
+        let calls = vec![call(0, 0x2000, 0x1004), call(0, 0x2000, 0x1030), call(0, 0x2000, 0x1064)];
+        assert_eq!(top_callers(&calls, &syms, 5, None), vec![("s1000".to_string(), 3)]);
+        assert_eq!(top_callers(&calls, &syms, 5, Some(0x40)),
+                   vec![("s1000".to_string(), 2), ("s1000+0x40".to_string(), 1)]);
+    }
+
+    fn call(cpu: u32, to: u64, from: u64) -> FnCall {
+        FnCall {
+            cpu,
+            from,
+            to,
+            duration: None,
+            ts: None,
+            to_named: false,
+            from_named: false,
+            to_name: None,
+            from_name: None,
+        }
+    }
+
+    #[test]
+    fn merge_timestamps() {
+        let at = |cpu, ts| FnCall { ts: Some(ts), ..call(cpu, 0, 0) };
+        let traces = vec![
+            vec![at(0, 10), at(0, 20), at(0, 40)],
+            vec![at(1, 5), at(1, 20), at(1, 50)],
+            vec![],
+        ];
+        let merged = merge_by_timestamp(traces);
+        let order: Vec<_> = merged.iter().map(|c| (c.cpu, c.ts.unwrap())).collect();
+        assert_eq!(order, vec![(1, 5), (0, 10), (0, 20), (1, 20), (0, 40), (1, 50)]);
+
+    }
+
+    /// (symbol, cpu, depth, number of children) of each node
+    fn shape(nodes: &[CallNode]) -> Vec<(&str, u32, usize, usize)> {
+        nodes.iter().map(|n| (n.symbol.as_str(), n.cpu, n.depth, n.children.len())).collect()
+    }
+
+    #[test]
+    fn call_tree() {
+        let syms = syms_at(&[0x1000, 0x2000, 0x3000, 0x4000]);
+        let calls = [
+            call(0, 0x1000, 0x500),     // s1000 from below every symbol: root
+            call(0, 0x2000, 0x1010),    // s2000 from s1000
+            call(1, 0x2000, 0x1010),    // other CPU with nothing open: root
+            call(0, 0x3000, 0x2010),    // s3000 from s2000
+            call(0, 0x3000, 0x2020),    // s3000 returned, called again
+            call(0, 0x4000, 0x1020),    // back in s1000, s2000 returned
+            call(0, 0x1000, 0x4f00),    // s4000 calls s1000
+        ];
+        let roots = build_call_tree(&calls, &syms);
+        assert_eq!(shape(&roots), vec![("s1000", 0, 0, 2), ("s2000", 1, 0, 0)]);
+
+        let top = &roots[0].children;
+        assert_eq!(shape(top), vec![("s2000", 0, 1, 2), ("s4000", 0, 1, 1)]);
+        assert_eq!(shape(&top[0].children), vec![("s3000", 0, 2, 0); 2]);
+        assert_eq!(shape(&top[1].children), vec![("s1000", 0, 2, 0)]);
+        assert_eq!(top[1].children[0].addr, 0x1000);
+    }
+
+    #[test]
+    fn recursion_folding() {
+        let syms = syms_at(&[0x1000, 0x2000, 0x3000]);
+        let calls = [
+            call(0, 0x1000, 0x500),     // s1000
+            call(0, 0x1000, 0x1010),    // recursing
+            call(0, 0x1000, 0x1010),    // deeper
+            call(0, 0x2000, 0x1020),    // s2000 from the innermost s1000
+            call(0, 0x3000, 0x2010),    // s3000 from s2000
+            call(0, 0x2000, 0x3010),    // s2000 again, but not directly
+        ];
+        let mut roots = build_call_tree(&calls, &syms);
+        fold_recursion(&mut roots);
+        assert_eq!(shape(&roots), vec![("s1000", 0, 0, 1)]);
+        assert_eq!(roots[0].calls, 3);
+        let s2000 = &roots[0].children[0];
+        assert_eq!(shape(&roots[0].children), vec![("s2000", 0, 1, 1)]);
+        assert_eq!(s2000.calls, 1);
+        assert_eq!(shape(&s2000.children), vec![("s3000", 0, 2, 1)]);
+        assert_eq!(shape(&s2000.children[0].children), vec![("s2000", 0, 3, 0)]);
+    }
+
+    /// CPUs of the calls parsed from a trace
+    fn cpus(trace: &str, cpu_hex: bool) -> Vec<u32> {
+        let opts = ParseOpts { cpu_hex, ..ParseOpts::default() };
+        ftrace(trace.as_bytes(), &opts).iter().map(|call| call.cpu).collect()
+    }
+
+    #[test]
+    fn timestamp_order() {
+        let at = |cpu, ts| FnCall { ts: Some(ts), ..call(cpu, 0, 0) };
+        // CPUs are checked separately
+        assert_eq!(timestamp_inversions(&[at(0, 10), at(1, 5), at(0, 20), at(1, 6)]), 0);
+        assert_eq!(timestamp_inversions(&[at(0, 10), at(0, 5), at(0, 7), at(0, 5)]), 2);
+        assert_eq!(timestamp_inversions(&[at(0, 10), at(0, 10), call(0, 0, 0)]), 0);
+    }
+
+    #[test]
+    fn cpu_decimal() {
+        assert_eq!(cpus("0 1000 2000 x\n12 1000 2000 x\n", false), vec![0, 12]);
+        assert_eq!(cpus("CPU:3 ts:100 1000 2000 x\n", false), vec![3]);
+    }
+
+    #[test]
+    fn cpu_prefixed() {
+        assert_eq!(cpus("CPU3: 1000 2000 x\nCPU#12 1000 2000 x\nCPU7 1000 2000 x\n", false),
+                   vec![3, 12, 7]);
+    }
+
+    #[test]
+    fn cpu_hex() {
+        assert_eq!(cpus("a 1000 2000 x\n10 1000 2000 x\nCPU1f: 1000 2000 x\n", true),
+                   vec![10, 16, 31]);
+        // Hex CPUs aren't decimal
+        assert_eq!(cpus("a 1000 2000 x\n", false), vec![]);
+    }
+
+    /// Durations of the calls parsed from a trace
+    fn durations(trace: &str) -> Vec<Option<f64>> {
+        ftrace(trace.as_bytes(), &ParseOpts::default()).iter().map(|call| call.duration).collect()
+    }
+
+    #[test]
+    fn call_durations() {
+        assert_eq!(durations("0 1000 2000  2.134 us  |  vfs_read();\n"), vec![Some(2.134)]);
+        assert_eq!(durations("0 1000 2000  vfs_read <- ksys_read\n"), vec![None]);
+        // Not a number followed by "us", just a name containing it
+        assert_eq!(durations("0 1000 2000  usb_probe <- really_probe.1\n"), vec![None]);
+        assert_eq!(durations("0 1000 2000  usb_probe <- 3.5usb\n"), vec![None]);
+    }
+
+    #[test]
+    fn symbol_times() {
+        let syms = syms_at(&[0x1000, 0x2000, 0x3000]);
+        let timed = |to, from, duration| FnCall { duration, ..call(0, to, from) };
+        let calls = vec![
+            timed(0x2000, 0x1004, Some(10.0)),
+            timed(0x3000, 0x2004, Some(4.0)),   // in the first s2000
+            timed(0x3000, 0x2008, Some(3.0)),   // and so is this one
+            timed(0x2000, 0x1008, Some(5.0)),
+            timed(0x3000, 0x2010, None),        // untimed, so nothing is taken off
+        ];
+        let times: Vec<_> = time_by_symbol(&calls, &syms, None).into_iter()
+            .map(|t| (t.name, t.calls, t.inclusive, t.exclusive))
+            .collect();
+        assert_eq!(times, vec![("s2000".to_string(), 2, 15.0, 8.0),
+                               ("s3000".to_string(), 2, 7.0, 7.0)]);
+    }
+
+    #[test]
+    fn merged_suffixes() {
+        assert_eq!(merge_suffixes("foo.cold"), "foo");
+        assert_eq!(merge_suffixes("foo.isra.0.cold"), "foo");
+        assert_eq!(merge_suffixes("__pfx_foo"), "foo");
+        assert_eq!(merge_suffixes("foo.constprop.0"), "foo");
+        assert_eq!(merge_suffixes("foo.llvm.123"), "foo.llvm.123");
+        assert_eq!(merge_suffixes("foo"), "foo");
+    }
+
+    #[test]
+    fn bracketed_names() {
+        let text = "1000 T foo[bar]baz\n2000 t weird] [ext4]\n3000 t a [b] c\n4000 t x\t[mod] ";
+        let syms = kallsyms(text.as_bytes(), &ParseOpts::default());
+        let parsed: Vec<_> = syms.values().map(|s| (&s.name[..], s.module.as_deref())).collect();
+        assert_eq!(parsed, vec![("foo[bar]baz", None), ("weird]", Some("ext4")),
+                                ("a [b] c", None), ("x", Some("mod"))]);
+    }
+
+    #[test]
+    fn parse_stats() {
+        let opts = ParseOpts { max_line_len: 20, ..ParseOpts::default() };
+        let text = "1000 T a\nnot a symbol line\n2000 T a_very_long_symbol_name\n3000 t c";
+        let (syms, stats) = kallsyms_with_stats(text.as_bytes(), &opts);
+        assert_eq!(syms.len(), 2);
+        assert_eq!(stats, ParseStats { total_lines: 4, matched: 2, skipped: 1, errors: 1 });
+    }
+
+    #[test]
+    fn debugfs_format() {
+        let syms = syms_at(&[0x1000, 0x2000]);
+        let trace = "# tracer: function\n\
+                     bash-1234  [002] d..1  12.000005: s2000 <-s1000+0x6d\n\
+                     kworker/0:1-17 ( 17) [000] ....  12.5: s2000+0x0/0x10 <-0x3004\n\
+                     sh-1  [001] 13.000001: s2000 <ffff0042> <-s1000 <0000fffe>\n\
+                     sh-1  [001] 13.000002: s2000 <-0xfffffffffffffffff\n";
+        let opts = ParseOpts::default();
+        let names = symbol_names(&syms);
+        let (calls, stats) = debugfs_with_stats(trace.as_bytes(), &opts, Some(&names));
+        let fields: Vec<_> = calls.iter().map(|c| (c.cpu, c.to, c.from, c.ts.unwrap())).collect();
+        assert_eq!(fields, vec![(2, 0x2000, 0x106d, 12_000_005), (0, 0x2000, 0x3004, 12_500_000),
+                                (1, 0xffff0042, 0xfffe, 13_000_001)]);
+        let named: Vec<_> = calls.iter().map(|c| (c.to_named, c.from_named)).collect();
+        assert_eq!(named, vec![(true, true), (true, false), (false, false)]);
+        assert_eq!(stats.errors, 1);
+    }
+
+    #[test]
+    fn debugfs_names_as_given() {
+        // Two static functions named show: the trace doesn't say which one
+        // is meant, so the name is kept, as is one kallsyms doesn't have
+        let mut syms = syms_at(&[0x1000, 0x2000, 0x3000]);
+        for &addr in &[0x1000, 0x2000] {
+            syms.get_mut(&addr).unwrap().name = "show".to_string();
+        }
+        let trace = "sh-1  [001] 13.000001: s3000 <-show+0x40\n\
+                     sh-1  [001] 13.000002: mydrv_irq <-s3000+0x8\n";
+        let names = symbol_names(&syms);
+        assert!(!names.contains_key("show"));
+        let (calls, stats) = debugfs_with_stats(trace.as_bytes(), &ParseOpts::default(),
+                                                Some(&names));
+        assert_eq!(stats.errors, 0);
+        let sites: Vec<_> = calls.iter()
+            .map(|c| (c.to, c.to_name.as_deref(), c.from, c.from_name.as_deref()))
+            .collect();
+        assert_eq!(sites, vec![(0x3000, None, 0, Some("show+0x40")),
+                               (0, Some("mydrv_irq"), 0x3008, None)]);
+        let opts = FormatOpts::default();
+        let lines: Vec<_> = calls.iter().map(|c| format_call(c, &syms, &opts)).collect();
+        assert_eq!(lines, vec!["1 s3000 <- show+0x40", "1 mydrv_irq <- s3000+0x8"]);
+        let callers: Vec<_> = top_callers(&calls, &syms, 2, None).into_iter()
+            .map(|(name, _)| name).collect();
+        assert_eq!(callers, vec!["s3000", "show"]);
+    }
+
+    #[test]
+    fn invalid_utf8_skipped() {
+        let trace = b"0 1000 2000 x\n\xff\xfe\x80 1000\n1 3000 \xc3 x\n1 4000 5000 \xff\n";
+        let (calls, stats) = ftrace_with_stats(&trace[..], &ParseOpts::default());
+        let calls: Vec<_> = calls.iter().map(|c| (c.cpu, c.to, c.from)).collect();
+        assert_eq!(calls, vec![(0, 0x1000, 0x2000), (1, 0x4000, 0x5000)]);
+        assert_eq!(stats, ParseStats { total_lines: 4, matched: 2, skipped: 0, errors: 2 });
+    }
+
+    #[test]
+    fn folded_stacks() {
+        let syms = syms_at(&[0x1000, 0x2000, 0x3000]);
+        let calls = vec![call(0, 0x2000, 0x1004), call(0, 0x3000, 0x2004),
+                         call(0, 0x3000, 0x2008), call(0, 0x2000, 0x1008)];
+        let samples: Vec<_> = folded_samples(&calls, &syms).into_iter().collect();
+        assert_eq!(samples, vec![("s1000;s2000".to_string(), 2),
+                                 ("s1000;s2000;s3000".to_string(), 2)]);
+    }
+
+    #[test]
+    fn stack_underflows() {
+        let syms = syms_at(&[0x1000, 0x2000, 0x3000, 0x4000]);
+        let calls = [
+            call(0, 0x2000, 0x1010),    // trace starts in s1000: not an underflow
+            call(1, 0x2000, 0x1010),    // nor on another CPU
+            call(0, 0x3000, 0x2010),    // s3000 from s2000
+            call(0, 0x2000, 0x1020),    // back in s1000, which was never seen
+            call(0, 0x3000, 0x2010),
+            call(0, 0x1000, 0x4010),    // s4000 wasn't seen either
+        ];
+        let (parents, underflows) = call_parents_with_underflows(&calls, &syms);
+        assert_eq!(parents, vec![None, None, Some(0), None, Some(3), None]);
+        assert_eq!(underflows.into_iter().collect::<Vec<_>>(), vec![(0, 2)]);
+    }
+
+    #[test]
+    fn index_round_trip() {
+        let mut syms = syms_at(&[0x1000, 0x2000]);
+        syms.insert(0x3000, Symbol {
+            name: "ext4_read".to_string(),
+            module: Some("ext4".to_string()),
+            sym_type: 't',
+            size: Some(0x40),
+        });
+        let mut index = Vec::new();
+        write_index(&mut index, &syms).unwrap();
+        let read = read_index(&index[..]).unwrap();
+        let fields = |syms: &Syms| -> Vec<_> {
+            syms.iter()
+                .map(|(&addr, s)| (addr, s.name.clone(), s.module.clone(), s.sym_type, s.size))
+                .collect()
+        };
+        assert_eq!(fields(&read), fields(&syms));
+
+        // A length running past the end of the file
+        let mut corrupt = index.clone();
+        let name_len = INDEX_MAGIC.len() + 4 + 8 + 8 + 1;
+        corrupt[name_len..name_len + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_index(&corrupt[..]).is_err());
+    }
+
+    #[test]
+    fn index_rejected() {
+        let mut index = Vec::new();
+        write_index(&mut index, &syms_at(&[0x1000])).unwrap();
+        let mut magic = index.clone();
+        magic[0] = b'X';
+        assert_eq!(read_index(&magic[..]).err().unwrap().to_string(), "not a symbol index");
+        let mut stale = index.clone();
+        stale[8..12].copy_from_slice(&(INDEX_VERSION - 1).to_le_bytes());
+        assert!(read_index(&stale[..]).err().unwrap().to_string().contains("rebuild it"));
+    }
+
+    #[test]
+    fn module_map() {
+        let text = "# prefix module\nnfs nfs\nnfs4 nfsv4\n\next4_ ext4\n";
+        let map = read_module_map(text.as_bytes()).unwrap();
+        let mut syms = kallsyms(&b"1000 t nfs4_open\n2000 t nfs_open\n3000 t vfs_open [x]\n"[..],
+                                &ParseOpts::default());
+        apply_module_map(&mut syms, &map);
+        let modules: Vec<_> = syms.values().map(|sym| sym.module.as_deref()).collect();
+        // The longest prefix wins, whatever order the map gives them in
+        assert_eq!(modules, vec![Some("nfsv4"), Some("nfs"), Some("x")]);
+    }
+
+    #[test]
+    fn module_map_malformed() {
+        for text in &["nfs\n", "nfs nfs extra\n"] {
+            let e = read_module_map(text.as_bytes()).err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn address_width_32() {
+        assert_eq!(detect_address_width(&syms_at(&[0xc0008000, 0xc0ffffff])), 32);
+        assert_eq!(detect_address_width(&syms_at(&[0xffffffff])), 32);
+        // Leading zeros stripped from a small address don't change the answer
+        assert_eq!(detect_address_width(&syms_at(&[0x8000])), 32);
+        assert_eq!(detect_address_width(&Syms::new()), 32);
+        // Swapped within 32 bits, so the address stays in the lower half
+        assert_eq!(byteswap(0x0080_00c0, 32), 0xc000_8000);
+        assert_eq!(byteswap(0xdead_beef_0080_00c0, 32), 0xc000_8000);
+    }
+
+    #[test]
+    fn address_width_64() {
+        assert_eq!(detect_address_width(&syms_at(&[0x1000, 0xffffffff81000000])), 64);
+        assert_eq!(detect_address_width(&syms_at(&[0x100000000])), 64);
+        assert_eq!(byteswap(0x0000_0081_ffff_ffff, 64), 0xffff_ffff_8100_0000);
+        assert_eq!(byteswap(byteswap(0xffffffff81000104, 64), 64), 0xffffffff81000104);
+    }
+}
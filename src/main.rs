@@ -1,145 +1,1453 @@
-#![feature(btree_range, collections_bound)]
+extern crate ftrace_ramoops_fmt;
 extern crate regex;
+#[cfg(feature = "mmap")]
+extern crate libc;
 
-use std::collections::BTreeMap;
-use std::io::{BufRead, BufReader};
-use std::fmt;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::Entry;
+use std::env;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, IsTerminal, Write};
 use std::fmt::Display;
+use std::fs;
 use std::fs::File;
 use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, SystemTime};
 use regex::Regex;
+use ftrace_ramoops_fmt::{CallNode, Dupes, FnCall, FormatOpts, ParseOpts, ParseStats, SymbolNames,
+                         Syms, apply_module_map, build_call_tree, byteswap,
+                         call_parents_with_underflows, debugfs_with_stats, detect_address_width,
+                         find_sym, fold_recursion, folded_samples, format_call, ftrace_with_stats,
+                         is_ftrace_internal, kallsyms_near, kallsyms_with_dupes,
+                         kallsyms_with_stats, merge_by_timestamp, merge_suffixes, percpu_range,
+                         read_index, read_module_map, resolve, site_function, symbol_names,
+                         text_start, time_by_symbol, timestamp_inversions, top_callees, top_callers,
+                         write_index};
 
-struct Symbol {
-    name: String,
-    module: Option<String>,
-}
+#[cfg(feature = "archive")]
+mod archive;
+mod config;
+#[cfg(feature = "mmap")]
+mod mmap;
+#[cfg(feature = "server")]
+mod server;
+mod stats;
 
-impl Display for Symbol {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if let Some(ref m) = self.module {
-            write!(f, "{}[{}]", self.name, m)
-        } else {
-            write!(f, "{}", self.name)
+/// Source frames for an address as (function, file:line), innermost first.
+type Inlines = BTreeMap<u64, Vec<(String, String)>>;
+
+/// Look up the inline chains of `addrs` in `vmlinux` with a single run of
+/// binutils' addr2line.
+fn addr2line_inlines(vmlinux: &str, addrs: &[u64]) -> Inlines {
+    let mut child = Command::new("addr2line")
+        .args(["-a", "-i", "-f", "-e", vmlinux])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run addr2line");
+
+    // Feed addresses from another thread so a full stdout pipe can't deadlock
+    // us against addr2line
+    let mut stdin = child.stdin.take().unwrap();
+    let input: String = addrs.iter().map(|addr| format!("{:x}\n", addr)).collect();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+
+    // Each address is echoed as 0x<addr>, followed by function/location
+    // line pairs
+    let mut inlines = Inlines::new();
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    let mut frames = None;
+    while let Some(line) = lines.next() {
+        let line = line.unwrap();
+        if let Some(hex) = line.strip_prefix("0x") {
+            if let Ok(addr) = u64::from_str_radix(hex, 16) {
+                frames = Some(inlines.entry(addr).or_default());
+                continue;
+            }
+        }
+        let location = lines.next().map(|l| l.unwrap()).unwrap_or_default();
+        if let Some(ref mut frames) = frames {
+            frames.push((line, location));
         }
     }
+    writer.join().unwrap().expect("Failed to write to addr2line");
+    child.wait().expect("Failed to wait for addr2line");
+    inlines
 }
 
-fn kallsyms<F: BufRead>(f: F) -> BTreeMap<u64, Symbol> {
-    let regex = r"(?x)
-        (?P<addr>[0-9a-fA-F]+)\s    # Address
-        (?P<type>[:alpha:])\s       # Type
-        (?P<name>\S+)               # Name
-        (?:\s+\[(?P<mod>\S+)\])?    # Optional module
-        ";
-    let regex = Regex::new(regex).unwrap();
-    f.lines().map(|line| {
-        let line = line.unwrap();
-        if let Some(caps) = regex.captures(&line) {
-            let addr = caps.name("addr").unwrap();
-            let addr = u64::from_str_radix(addr, 16).expect("Failed to parse address");
-            let name = caps.name("name").unwrap().to_string();
-            let module = caps.name("mod").map(|x| x.to_string());
-            (addr, Symbol {
-                name: name,
-                module: module,
-            })
-        } else {
-            panic!("Symbol line not matched: {}", line);
+/// Function names from addr2line for addresses kallsyms couldn't resolve
+type Fallback = BTreeMap<u64, String>;
+
+/// Look up the functions containing `addrs` in `vmlinux` with a single run
+/// of addr2line. Addresses it doesn't know are left out.
+fn addr2line_names(vmlinux: &str, addrs: &[u64]) -> Fallback {
+    addr2line_inlines(vmlinux, addrs).into_iter()
+        // The outermost frame is the function the address is in
+        .filter_map(|(addr, frames)| frames.last().map(|(function, _)| (addr, function.clone())))
+        .filter(|(_, function)| function != "??")
+        .collect()
+}
+
+/// Alignment assumed for function entry points. Most architectures align
+/// functions to at least this much; x86 with function alignment uses 16.
+const SANITY_ALIGN: u64 = 4;
+/// Fraction of aligned callee addresses below which the trace is suspect.
+const SANITY_MIN_ALIGNED: f64 = 0.5;
+
+/// Warn if the callee addresses don't look like function entry points. A
+/// mostly unaligned set of `to` addresses usually means the columns were
+/// misparsed or the trace was byte-swapped.
+fn sanity_check(calls: &[FnCall], width: u32) {
+    if calls.is_empty() {
+        return;
+    }
+    if width == 32 {
+        let wide = calls.iter().filter(|call| call.to > u32::MAX as u64).count();
+        if wide > 0 {
+            eprintln!("Warning: {} callee addresses don't fit the 32-bit kernel addresses \
+                       of kallsyms", wide);
         }
-    }).collect()
+    }
+    let aligned = calls.iter().filter(|call| call.to % SANITY_ALIGN == 0).count();
+    let fraction = aligned as f64 / calls.len() as f64;
+    if fraction < SANITY_MIN_ALIGNED {
+        eprintln!("Warning: only {:.1}% of callee addresses are {}-byte aligned; \
+                   the trace may be misparsed or byte-swapped",
+                  fraction * 100.0, SANITY_ALIGN);
+    }
 }
 
-type Syms = BTreeMap<u64, Symbol>;
+fn report_dupes(dupes: &Dupes) {
+    for (addr, names) in dupes {
+        let (kept, dropped) = names.split_last().unwrap();
+        eprintln!("Duplicate symbols at {:x}: kept {}, dropped {}", addr, kept, dropped.join(", "));
+    }
+}
 
-struct SymOffset<'a> {
-    addr: u64,
-    offset: u64,
-    sym: &'a Symbol,
+/// What was read from the kallsyms files
+struct Kallsyms {
+    /// After --merge-suffixes and --module-map
+    syms: Syms,
+    /// Lines read, for the exit code and --stats
+    lines: ParseStats,
+    /// The symbols' names as read, for a debugfs trace
+    names: Option<SymbolNames>,
 }
 
-impl<'a> Display for SymOffset<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.offset > 0 {
-            write!(f, "{}+0x{:x}", self.sym, self.offset)
-        } else {
-            write!(f, "{}", self.sym)
+/// Read and merge every kallsyms, those in --archive first. A symbol from a
+/// later file replaces one at the same address from an earlier file.
+fn load_kallsyms(opts: &Options) -> (Syms, ParseStats) {
+    let mut syms = Syms::new();
+    let mut stats = ParseStats::default();
+    for (name, contents) in &opts.archived.kallsyms {
+        if !opts.quiet {
+            eprintln!("Reading kallsyms from {}", name);
         }
+        // Archives hold kallsyms as text, even alongside --index
+        let (file_syms, file_stats) = parse_kallsyms_text(&contents[..], opts);
+        syms.extend(file_syms);
+        stats.add(&file_stats);
     }
+    for path in &opts.kallsyms {
+        let (file_syms, file_stats) = read_kallsyms(path, opts);
+        syms.extend(file_syms);
+        stats.add(&file_stats);
+    }
+    (syms, stats)
 }
 
-fn find_sym(needle: u64, syms: &Syms) -> Option<SymOffset> {
-    use std::collections::Bound;
-    // Most efficient way (I can find as of Rust 1.11) to search for the
-    // closest <= element. range() internally finds the first and last nodes
-    // immediately. next_back() of the DoubleEndedIterator returns the last
-    // node directly. Avoid last() on the Iterator because it uses the default
-    // implementation that iterates sequentially and takes ~10 seconds for the
-    // whole file.
-    //
-    // Unbounded range on the left still traverses to the left-most node which
-    // is technically unnecessary work.
-    syms.range(Bound::Unbounded, Bound::Included(&needle)).next_back()
-        .map(|(addr, sym)| (SymOffset { addr: *addr, offset: needle - addr, sym: sym }))
+fn read_kallsyms<P: AsRef<Path> + Display>(path: P, opts: &Options) -> (Syms, ParseStats) {
+    if !opts.quiet {
+        eprintln!("Reading kallsyms from {}", path);
+    }
+    let f = File::open(path).unwrap();
+    if opts.mmap {
+        match map_file(&f) {
+            Ok(map) => return parse_kallsyms(&map[..], opts),
+            Err(e) => eprintln!("Warning: can't map kallsyms, reading it instead: {}", e),
+        }
+    }
+    parse_kallsyms(BufReader::new(f), opts)
 }
 
-struct FnCall {
-    cpu: u32,
-    from: u64,
-    to: u64,
+#[cfg(feature = "mmap")]
+fn map_file(f: &File) -> io::Result<mmap::Mmap> {
+    mmap::Mmap::map(f)
 }
 
-fn ftrace<F: BufRead>(f: F) -> Vec<FnCall> {
-    let regex = r"(?x)
-        (?P<cpu>\d+)\s+             # CPU
-        (?P<to>[0-9a-fA-F]+)\s+     # To Addr
-        (?P<from>[0-9a-fA-F]+)\s+   # From Addr
-        ";
-    let regex = Regex::new(regex).unwrap();
-    f.lines().map(|line| {
-        let line = line.unwrap();
-        let caps = regex.captures(&line).expect("Failed to match ftrace line");
-        let s_cpu = caps.name("cpu").unwrap();
-        let cpu = u32::from_str_radix(s_cpu, 10).expect("Failed to parse CPU");
-        let s_from = caps.name("from").unwrap();
-        let from = u64::from_str_radix(s_from, 16).expect("Failed to parse address");
-        let s_to = caps.name("to").unwrap();
-        let to = u64::from_str_radix(s_to, 16).expect("Failed to parse address");
-        FnCall {
-            cpu: cpu,
-            from: from,
-            to: to,
-        }
-    }).collect()
-}
-
-fn read_kallsyms<P: AsRef<Path> + Display>(path: P) -> Syms {
-    println!("Reading kallsyms from {}", path);
-    let f = File::open(path).unwrap();
-    let reader = BufReader::new(f);
+#[cfg(not(feature = "mmap"))]
+fn map_file(_f: &File) -> io::Result<Vec<u8>> {
+    panic!("--mmap requires building with --features mmap");
+}
+
+/// The symbols and, unless read from an index, the kallsyms lines parsed
+fn parse_kallsyms<R: BufRead>(reader: R, opts: &Options) -> (Syms, ParseStats) {
+    if opts.index {
+        let syms = read_index(reader)
+            .unwrap_or_else(|e| panic!("Failed to read symbol index: {}", e));
+        (syms, ParseStats::default())
+    } else {
+        parse_kallsyms_text(reader, opts)
+    }
+}
+
+fn parse_kallsyms_text<R: BufRead>(reader: R, opts: &Options) -> (Syms, ParseStats) {
+    if opts.lazy {
+        let targets: Vec<u64> = opts.addrs.iter()
+            .filter_map(|addr| parse_hex(addr))
+            .map(|addr| opts.relocate(addr))
+            .collect();
+        kallsyms_near(reader, &opts.parse, &targets, opts.output.exported_only)
+    } else if opts.report_dupes {
+        let (syms, dupes, stats) = kallsyms_with_dupes(reader, &opts.parse);
+        report_dupes(&dupes);
+        (syms, stats)
+    } else {
+        kallsyms_with_stats(reader, &opts.parse)
+    }
+}
 
-    // Read kallsyms
-    kallsyms(reader)
+/// Shell-style match of `*` and `?`
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    // Retry from the last * on a mismatch, letting it absorb one more byte
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(&b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
 }
 
-fn read_ftrace<P: AsRef<Path> + Display>(path: P) -> Vec<FnCall> {
-    println!("Reading ftrace from {}", path);
+/// Expand wildcards in the file name part of a path, for shells (or no
+/// shell at all) that leave them alone. Matches are sorted. A path with no
+/// matches is returned as is.
+fn expand_glob(path: &str) -> Vec<String> {
+    let (dir, pattern) = match path.rfind('/') {
+        Some(slash) => (&path[..slash + 1], &path[slash + 1..]),
+        None => ("", path),
+    };
+    if !pattern.contains(['*', '?']) {
+        return vec![path.to_string()];
+    }
+    let mut matches: Vec<String> = fs::read_dir(if dir.is_empty() { "." } else { dir })
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        // As in the shell, wildcards don't match a leading dot
+        .filter(|name| !name.starts_with('.') || pattern.starts_with('.'))
+        .filter(|name| glob_match(pattern.as_bytes(), name.as_bytes()))
+        .map(|name| format!("{}{}", dir, name))
+        .collect();
+    if matches.is_empty() {
+        return vec![path.to_string()];
+    }
+    matches.sort();
+    matches
+}
+
+fn read_ftrace<P: AsRef<Path> + Display>(path: P, opts: &Options, names: Option<&SymbolNames>)
+                                         -> (Vec<FnCall>, ParseStats) {
+    if !opts.quiet {
+        eprintln!("Reading ftrace from {}", path);
+    }
     let f = File::open(path).unwrap();
     let reader = BufReader::new(f);
 
     // Read ftrace
-    ftrace(reader)
+    parse_ftrace(reader, opts, names)
+}
+
+/// Parse a trace in the --input-format. `names` gives the addresses of
+/// functions a debugfs trace names, see `debugfs_with_stats`.
+fn parse_ftrace<R: BufRead>(reader: R, opts: &Options, names: Option<&SymbolNames>)
+                            -> (Vec<FnCall>, ParseStats) {
+    match opts.input_format {
+        InputFormat::Ramoops => ftrace_with_stats(reader, &opts.parse),
+        InputFormat::Debugfs => debugfs_with_stats(reader, &opts.parse, names),
+    }
+}
+
+/// Read every trace, those in --archive after the files, as (name, calls,
+/// stats)
+fn read_traces(opts: &Options, names: Option<&SymbolNames>)
+               -> Vec<(String, Vec<FnCall>, ParseStats)> {
+    let mut traces = Vec::new();
+    for path in &opts.traces {
+        let (calls, stats) = read_ftrace(path, opts, names);
+        traces.push((path.clone(), calls, stats));
+    }
+    for (name, contents) in &opts.archived.traces {
+        if !opts.quiet {
+            eprintln!("Reading ftrace from {}", name);
+        }
+        let (calls, stats) = parse_ftrace(&contents[..], opts, names);
+        traces.push((name.clone(), calls, stats));
+    }
+    traces
+}
+
+/// Members of an --archive as (name, contents)
+#[derive(Default)]
+struct Archived {
+    kallsyms: Vec<(String, Vec<u8>)>,
+    traces: Vec<(String, Vec<u8>)>,
+}
+
+#[cfg(feature = "archive")]
+use archive::read_archive;
+
+#[cfg(not(feature = "archive"))]
+fn read_archive(_path: &str) -> io::Result<Archived> {
+    panic!("--archive requires building with --features archive");
+}
+
+/// How calls are printed
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    /// `cpu callee <- caller`, with durations and inline frames
+    Text,
+    /// `cpu) callee <- caller`, like the kernel's function tracer
+    Ftrace,
+    /// `caller;callee;... count` stacks of every call, for FlameGraph
+    FoldedSamples,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "ftrace" => Ok(Format::Ftrace),
+            "folded-samples" => Ok(Format::FoldedSamples),
+            _ => Err(format!("unknown format {}, expected text, ftrace or folded-samples", s)),
+        }
+    }
+}
+
+/// How trace lines are laid out
+#[derive(Clone, Copy, PartialEq)]
+enum InputFormat {
+    /// `cpu callee caller`, as in pstore's ftrace-ramoops
+    Ramoops,
+    /// The function tracer's live trace file in debugfs or tracefs
+    Debugfs,
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<InputFormat, String> {
+        match s {
+            "ramoops" => Ok(InputFormat::Ramoops),
+            "debugfs" => Ok(InputFormat::Debugfs),
+            _ => Err(format!("unknown input format {}, expected ramoops or debugfs", s)),
+        }
+    }
+}
+
+struct Options {
+    /// Paths of the kallsyms, later ones winning where addresses collide, or
+    /// of the prebuilt index if `index` is set
+    kallsyms: Vec<String>,
+    index: bool,
+    /// Write the parsed kallsyms to this index file instead of resolving
+    build_index: Option<String>,
+    /// Trace files, read in order. Required unless building an index.
+    traces: Vec<String>,
+    /// Warn about traces whose addresses look implausible
+    sanity: bool,
+    /// Reverse the byte order of the trace addresses before resolving
+    byteswap: bool,
+    /// Print a comment line describing the columns and inputs
+    header: bool,
+    /// Don't print progress messages to stderr
+    quiet: bool,
+    /// Report kallsyms addresses that have more than one name
+    report_dupes: bool,
+    /// Show the inline chain of each call site using this vmlinux's debug info
+    inlines: Option<String>,
+    /// Name addresses kallsyms can't resolve from this vmlinux
+    addr2line: Option<String>,
+    /// Only show calls that took longer than this many microseconds
+    slow: Option<f64>,
+    /// Fold compiler clone suffixes into the parent function name
+    merge_suffixes: bool,
+    /// File of symbol name prefixes and the modules to show for them
+    module_map: Option<String>,
+    /// Draw the reconstructed call tree instead of the calls
+    tree: bool,
+    ascii_tree: bool,
+    /// Draw direct recursion in the tree as one annotated frame
+    fold_recursion: bool,
+    /// Print time spent per function instead of the calls
+    time_by_symbol: bool,
+    /// Print the N most called functions instead of the calls
+    top_callees: Option<usize>,
+    /// Print the N functions making the most calls instead of the calls
+    top_callers: Option<usize>,
+    /// Group report entries by offset rounded down to this, not just by name
+    offset_granularity: Option<u64>,
+    /// Only keep calls between different modules
+    cross_module: bool,
+    /// Drop calls to or from ftrace itself
+    hide_ftrace_internals: bool,
+    /// Only keep calls from the first call to this function
+    start_symbol: Option<String>,
+    /// Only keep calls up to the last call to this function
+    stop_symbol: Option<String>,
+    /// Only keep calls where either function's name matches
+    filter: Option<Regex>,
+    glob: Option<String>,
+    /// Keep re-running whenever a trace file changes
+    watch: bool,
+    /// KASLR slide subtracted from trace addresses
+    kaslr_offset: Option<u64>,
+    /// Slide added to trace addresses
+    add_offset: Option<u64>,
+    format: Format,
+    input_format: InputFormat,
+    /// Print a divider before each call to this function
+    split_on: Option<String>,
+    /// Only count the calls, without kallsyms
+    count_only: bool,
+    /// Print the parsed calls as hex, without kallsyms
+    raw: bool,
+    /// Sort each trace by timestamp before merging
+    sort_time: bool,
+    /// Print a summary of the run to stderr
+    stats: bool,
+    /// Page the output when it goes to a terminal
+    pager: bool,
+    /// Write the run's aggregates to this file as JSON
+    stats_json: Option<String>,
+    /// Only load the symbols needed for the --addr lookups
+    lazy: bool,
+    /// Parse kallsyms from a memory map instead of buffered reads
+    mmap: bool,
+    /// kallsyms and traces read from --archive
+    archived: Archived,
+    /// Look up these addresses, as given, instead of reading traces
+    addrs: Vec<String>,
+    lookup: bool,
+    /// Serve lookups over HTTP instead of reading traces
+    serve: bool,
+    listen: String,
+    parse: ParseOpts,
+    output: FormatOpts,
+}
+
+/// Where `serve` listens unless --listen is given
+const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+
+fn flag_value<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> String {
+    args.next().unwrap_or_else(|| panic!("{} requires a value", flag))
+}
+
+fn flag_number<I: Iterator<Item = String>, T: FromStr>(args: &mut I, flag: &str) -> T {
+    flag_value(args, flag).parse().unwrap_or_else(|_| panic!("{} requires a number", flag))
+}
+
+/// Parse a hex address with or without a 0x prefix
+fn parse_hex(s: &str) -> Option<u64> {
+    let hex = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+    u64::from_str_radix(hex, 16).ok()
+}
+
+/// `s` as a quoted JSON string
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Split a list of addresses on commas and whitespace. Stack dump brackets
+/// such as `[<ffffffff81000100>]` are dropped.
+fn split_addrs(list: &str) -> Vec<String> {
+    list.split(|c: char| c == ',' || c.is_whitespace())
+        .map(|addr| addr.trim_start_matches("[<").trim_end_matches(">]"))
+        .filter(|addr| !addr.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Split a --cpu-label into its CPU range and name, `N=NAME` or
+/// `N-M=NAME`
+fn parse_cpu_label(label: &str) -> Option<((u32, u32), &str)> {
+    let (cpus, name) = label.split_once('=')?;
+    let cpus = match cpus.split_once('-') {
+        Some((first, last)) => (first.parse().ok()?, last.parse().ok()?),
+        None => {
+            let cpu = cpus.parse().ok()?;
+            (cpu, cpu)
+        }
+    };
+    Some((cpus, name))
+}
+
+fn flag_hex<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> u64 {
+    let value = flag_value(args, flag);
+    parse_hex(&value).unwrap_or_else(|| panic!("{} requires a hex value, got {}", flag, value))
+}
+
+impl Options {
+    fn parse<I: Iterator<Item = String>>(args: I, default_kallsyms: Vec<String>) -> Options {
+        let mut args = args.peekable();
+        let mut opts = Options {
+            kallsyms: Vec::new(),
+            index: false,
+            build_index: None,
+            traces: Vec::new(),
+            sanity: false,
+            byteswap: false,
+            header: false,
+            quiet: false,
+            report_dupes: false,
+            inlines: None,
+            addr2line: None,
+            slow: None,
+            merge_suffixes: false,
+            module_map: None,
+            tree: false,
+            ascii_tree: false,
+            fold_recursion: false,
+            time_by_symbol: false,
+            top_callees: None,
+            top_callers: None,
+            offset_granularity: None,
+            cross_module: false,
+            hide_ftrace_internals: false,
+            start_symbol: None,
+            stop_symbol: None,
+            filter: None,
+            glob: None,
+            watch: false,
+            kaslr_offset: None,
+            add_offset: None,
+            mmap: false,
+            archived: Archived::default(),
+            lazy: false,
+            stats: false,
+            pager: false,
+            stats_json: None,
+            sort_time: false,
+            count_only: false,
+            raw: false,
+            split_on: None,
+            format: Format::Text,
+            input_format: InputFormat::Ramoops,
+            addrs: Vec::new(),
+            lookup: false,
+            serve: false,
+            listen: DEFAULT_LISTEN.to_string(),
+            parse: ParseOpts::default(),
+            output: FormatOpts::default(),
+        };
+        if args.peek().is_some_and(|arg| arg == "serve") {
+            args.next();
+            opts.serve = true;
+        }
+        let mut positional = Vec::new();
+        let mut index = None;
+        let mut inlines = false;
+        let mut vmlinux = None;
+        let mut none_addrs = Vec::new();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--index" => index = Some(flag_value(&mut args, &arg)),
+                "--kallsyms" => opts.kallsyms.push(flag_value(&mut args, &arg)),
+                "--trace" => opts.traces.extend(expand_glob(&flag_value(&mut args, &arg))),
+                "--build-index" => opts.build_index = Some(flag_value(&mut args, &arg)),
+                "--arrow" => opts.output.arrow = flag_value(&mut args, &arg),
+                "--sanity" => opts.sanity = true,
+                "--byteswap" => opts.byteswap = true,
+                "--header" => opts.header = true,
+                "--quiet" => opts.quiet = true,
+                "--report-dupes" => opts.report_dupes = true,
+                "--inlines" => inlines = true,
+                "--vmlinux" => vmlinux = Some(flag_value(&mut args, &arg)),
+                "--addr2line" => opts.addr2line = Some(flag_value(&mut args, &arg)),
+                "--resolve-to-only" => opts.output.resolve_from = false,
+                "--resolve-from-only" => opts.output.resolve_to = false,
+                "--merge-suffixes" => opts.merge_suffixes = true,
+                "--module-map" => opts.module_map = Some(flag_value(&mut args, &arg)),
+                "--time-by-symbol" => opts.time_by_symbol = true,
+                "--tree" => opts.tree = true,
+                "--ascii-tree" => {
+                    opts.tree = true;
+                    opts.ascii_tree = true;
+                }
+                "--fold-recursion" => {
+                    opts.tree = true;
+                    opts.fold_recursion = true;
+                }
+                "--top-callees" => opts.top_callees = Some(flag_number(&mut args, &arg)),
+                "--top-callers" => opts.top_callers = Some(flag_number(&mut args, &arg)),
+                "--offset-granularity" => {
+                    let granularity = flag_hex(&mut args, &arg);
+                    if granularity == 0 {
+                        panic!("--offset-granularity must be greater than 0");
+                    }
+                    opts.offset_granularity = Some(granularity);
+                }
+                "--cross-module" => opts.cross_module = true,
+                "--hide-ftrace-internals" => opts.hide_ftrace_internals = true,
+                "--filter" => {
+                    let regex = flag_value(&mut args, &arg);
+                    opts.filter = Some(Regex::new(&regex)
+                        .unwrap_or_else(|e| panic!("Invalid --filter regex {}: {}", regex, e)));
+                }
+                "--glob" => opts.glob = Some(flag_value(&mut args, &arg)),
+                "--start-symbol" => opts.start_symbol = Some(flag_value(&mut args, &arg)),
+                "--stop-symbol" => opts.stop_symbol = Some(flag_value(&mut args, &arg)),
+                "--exported-only" => opts.output.exported_only = true,
+                "--anonymize" => opts.output.anonymize = true,
+                "--highlight" => {
+                    opts.output.highlight.insert(flag_value(&mut args, &arg));
+                }
+                "--none-addr" => none_addrs.push(flag_hex(&mut args, &arg)),
+                "--unresolved-marker" => {
+                    opts.output.unresolved_marker = Some(flag_value(&mut args, &arg))
+                }
+                "--watch" => opts.watch = true,
+                "--kaslr-offset" => opts.kaslr_offset = Some(flag_hex(&mut args, &arg)),
+                "--add-offset" => opts.add_offset = Some(flag_hex(&mut args, &arg)),
+                "--max-line-len" => opts.parse.max_line_len = flag_number(&mut args, &arg),
+                "--cpu-hex" => opts.parse.cpu_hex = true,
+                "--cpu-label" => {
+                    let label = flag_value(&mut args, &arg);
+                    let (cpus, name) = parse_cpu_label(&label)
+                        .unwrap_or_else(|| panic!("--cpu-label requires N=NAME, got {}", label));
+                    for cpu in cpus.0..=cpus.1 {
+                        opts.output.cpu_labels.insert(cpu, name.to_string());
+                    }
+                }
+                "--addr" => {
+                    opts.addrs.extend(split_addrs(&flag_value(&mut args, &arg)));
+                    opts.lookup = true;
+                }
+                "--addr-file" => {
+                    let path = flag_value(&mut args, &arg);
+                    let text = fs::read_to_string(&path)
+                        .unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e));
+                    opts.addrs.extend(split_addrs(&text));
+                    opts.lookup = true;
+                }
+                "--listen" => opts.listen = flag_value(&mut args, &arg),
+                "--mmap" => opts.mmap = true,
+                "--archive" => {
+                    let path = flag_value(&mut args, &arg);
+                    let archived = read_archive(&path)
+                        .unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e));
+                    opts.archived.kallsyms.extend(archived.kallsyms);
+                    opts.archived.traces.extend(archived.traces);
+                }
+                "--lazy" => opts.lazy = true,
+                "--stats" => opts.stats = true,
+                "--pager" => opts.pager = true,
+                "--stats-json" => opts.stats_json = Some(flag_value(&mut args, &arg)),
+                "--sort-time" => opts.sort_time = true,
+                "--count-only" => opts.count_only = true,
+                "--raw" => opts.raw = true,
+                "--offset-detail" => opts.output.offset_detail = true,
+                "--split-on" => opts.split_on = Some(flag_value(&mut args, &arg)),
+                "--max-name-len" => opts.output.max_name_len = Some(flag_number(&mut args, &arg)),
+                "--format" => {
+                    opts.format = flag_value(&mut args, &arg).parse()
+                        .unwrap_or_else(|e| panic!("--format: {}", e))
+                }
+                "--input-format" => {
+                    opts.input_format = flag_value(&mut args, &arg).parse()
+                        .unwrap_or_else(|e| panic!("--input-format: {}", e))
+                }
+                "--slow" => opts.slow = Some(flag_number(&mut args, &arg)),
+                _ if arg.starts_with("--") => panic!("Unknown option {}", arg),
+                _ => positional.push(arg),
+            }
+        }
+
+        // A prebuilt index stands in for the kallsyms argument, and building
+        // one doesn't need a trace
+        let needs_trace = opts.build_index.is_none() && !opts.lookup && !opts.serve;
+        let mut positional = positional.into_iter();
+        if opts.count_only || opts.raw {
+            // Nothing is resolved, so every argument is a trace
+        } else if let Some(index) = index {
+            if !opts.kallsyms.is_empty() {
+                panic!("--index and --kallsyms are mutually exclusive");
+            }
+            opts.kallsyms.push(index);
+            opts.index = true;
+        } else if opts.kallsyms.is_empty() {
+            // The default kallsyms is used unless the arguments start with
+            // one: a lone argument is the trace when one is needed
+            let traces_given = !opts.traces.is_empty() || !opts.archived.traces.is_empty();
+            let kallsyms_given = positional.len() > 1 ||
+                positional.len() == 1 && (!needs_trace || traces_given);
+            if !opts.archived.kallsyms.is_empty() ||
+                !default_kallsyms.is_empty() && !kallsyms_given {
+                opts.kallsyms = default_kallsyms;
+            } else {
+                opts.kallsyms.push(positional.next().expect("First argument must be kallsyms"));
+            }
+        }
+        opts.traces.extend(positional.flat_map(|path| expand_glob(&path)));
+        if opts.traces.is_empty() && opts.archived.traces.is_empty() && needs_trace {
+            panic!("Second argument must be pstore ftrace output");
+        }
+
+        if !none_addrs.is_empty() {
+            opts.output.none_addrs = none_addrs;
+        }
+        opts.output.ftrace = opts.format == Format::Ftrace;
+        if inlines {
+            opts.inlines = Some(vmlinux.expect("--inlines requires --vmlinux"));
+        }
+        if opts.raw && opts.input_format == InputFormat::Debugfs {
+            panic!("--raw only works for ramoops traces, debugfs ones are already symbolic");
+        }
+        // Only the list of calls is anonymized
+        if opts.output.anonymize && (opts.raw || opts.lookup || opts.serve || opts.reports() ||
+                                     opts.format == Format::FoldedSamples ||
+                                     opts.stats_json.is_some()) {
+            panic!("--anonymize only applies to the list of calls, it can't be used with --raw, \
+                    --addr, serve, reports, --format folded-samples or --stats-json");
+        }
+        if opts.lazy && (!opts.lookup || opts.index || opts.build_index.is_some()) {
+            panic!("--lazy only works for --addr lookups in a kallsyms file");
+        }
+        if opts.kaslr_offset.is_some() && opts.add_offset.is_some() {
+            panic!("--kaslr-offset and --add-offset are mutually exclusive");
+        }
+        if !opts.output.resolve_to && !opts.output.resolve_from {
+            panic!("--resolve-to-only and --resolve-from-only are mutually exclusive");
+        }
+        opts
+    }
+
+    /// Apply --kaslr-offset or --add-offset to a looked up address
+    fn relocate(&self, addr: u64) -> u64 {
+        match (self.kaslr_offset, self.add_offset) {
+            (Some(offset), _) => addr.saturating_sub(offset),
+            (_, Some(offset)) => addr.saturating_add(offset),
+            _ => addr,
+        }
+    }
+
+    /// Whether a report replaces the list of calls
+    fn reports(&self) -> bool {
+        self.tree || self.time_by_symbol || self.top_callees.is_some() ||
+            self.top_callers.is_some()
+    }
 }
 
 fn main() {
-    let tracefile = std::env::args().nth(2).expect("Second argument must be pstore ftrace output");
-    let calls = read_ftrace(tracefile);
-    let symsfile = std::env::args().nth(1).expect("First argument must be kallsyms");
-    let syms = read_kallsyms(symsfile);
+    let (args, default_kallsyms) = config::with_defaults(std::env::args().skip(1).collect());
+    let opts = Options::parse(args.into_iter(), default_kallsyms);
+    if opts.count_only {
+        process::exit(count_calls(&opts).exit_code());
+    }
+    if opts.raw {
+        process::exit(print_raw(&opts).exit_code());
+    }
+    if let Some(ref path) = opts.build_index {
+        let (syms, _) = load_kallsyms(&opts);
+        let f = File::create(path).unwrap();
+        write_index(BufWriter::new(f), &syms).expect("Failed to write symbol index");
+        return;
+    }
+
+    let (mut syms, lines) = load_kallsyms(&opts);
+    // Debugfs traces name functions as the kernel does, before any renaming
+    let names = Some(symbol_names(&syms)).filter(|_| opts.input_format == InputFormat::Debugfs);
+    if opts.merge_suffixes {
+        for sym in syms.values_mut() {
+            let merged = merge_suffixes(&sym.name).to_string();
+            sym.name = merged;
+        }
+    }
+    if let Some(ref path) = opts.module_map {
+        let f = File::open(path).unwrap_or_else(|e| panic!("Failed to open {}: {}", path, e));
+        let map = read_module_map(BufReader::new(f))
+            .unwrap_or_else(|e| panic!("Failed to read module map {}: {}", path, e));
+        apply_module_map(&mut syms, &map);
+    }
+
+    if opts.serve {
+        serve(&opts, &syms);
+    }
+    let kallsyms = Kallsyms { syms, lines, names };
+    let health = if opts.lookup {
+        lookup(&opts, &kallsyms.syms, &kallsyms.lines)
+    } else if opts.watch {
+        watch(&opts, &kallsyms)
+    } else {
+        run(&opts, &kallsyms)
+    };
+    process::exit(health.exit_code());
+}
 
-    // Search
+/// How often --watch checks the trace files for changes
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Re-run whenever a trace file's modification time changes, keeping the
+/// parsed kallsyms. Waits while any of the files is missing, as it may be
+/// in the middle of being rewritten.
+fn watch(opts: &Options, kallsyms: &Kallsyms) -> ! {
+    let mut last = None;
+    loop {
+        let mtimes: Option<Vec<SystemTime>> = opts.traces.iter()
+            .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect();
+        if mtimes.is_some() && mtimes != last {
+            // Clear the terminal and home the cursor
+            print!("\x1b[2J\x1b[H");
+            run(opts, kallsyms);
+            last = mtimes;
+        }
+        thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+#[cfg(feature = "server")]
+use server::serve;
+use stats::Stats;
+
+#[cfg(not(feature = "server"))]
+fn serve(_opts: &Options, _syms: &Syms) -> ! {
+    panic!("serve requires building with --features server");
+}
+
+/// Exit code when some addresses didn't resolve to a symbol
+const EXIT_UNRESOLVED: i32 = 2;
+/// Exit code when some input couldn't be parsed. Takes precedence over
+/// EXIT_UNRESOLVED.
+const EXIT_PARSE_ERRORS: i32 = 3;
+
+/// Problems met while resolving, reported through the exit code so scripts
+/// can tell whether the kallsyms and trace match.
+#[derive(Default)]
+struct Health {
+    unresolved: usize,
+    /// Unparsed kallsyms and trace lines, and unparsed --addr values
+    parse_errors: usize,
+    /// Lines read from the kallsyms files
+    kallsyms_lines: ParseStats,
+    /// Lines read from the traces
+    trace_lines: ParseStats,
+    /// Calls printed from an already formatted line, when calls were listed
+    line_cache_hits: Option<usize>,
+}
+
+impl Health {
+    /// Health starting from the kallsyms parsed, whose errors count
+    fn with_kallsyms(kallsyms_lines: &ParseStats) -> Health {
+        Health {
+            parse_errors: kallsyms_lines.errors,
+            kallsyms_lines: *kallsyms_lines,
+            ..Health::default()
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        if self.parse_errors > 0 {
+            EXIT_PARSE_ERRORS
+        } else if self.unresolved > 0 {
+            EXIT_UNRESOLVED
+        } else {
+            0
+        }
+    }
+}
+
+/// Print the number of calls in the traces, then the number on each CPU.
+fn count_calls(opts: &Options) -> Health {
+    let mut health = Health::default();
+    let mut per_cpu = BTreeMap::new();
+    let mut total = 0;
+    for (_, calls, stats) in read_traces(opts, None) {
+        health.parse_errors += stats.errors;
+        total += calls.len();
+        for call in calls {
+            *per_cpu.entry(call.cpu).or_insert(0) += 1;
+        }
+    }
+    write_output(opts, |out| {
+        writeln!(out, "{}", total)?;
+        for (cpu, count) in opts.output.by_cpu_label(&per_cpu) {
+            writeln!(out, "cpu {}: {}", cpu, count)?;
+        }
+        Ok(())
+    });
+    health
+}
+
+/// Print each call as parsed, `cpu from to` with the addresses in hex, to
+/// tell parsing problems from resolution ones.
+fn print_raw(opts: &Options) -> Health {
+    let mut health = Health::default();
+    let traces = read_traces(opts, None);
+    for (_, _, stats) in &traces {
+        health.parse_errors += stats.errors;
+    }
+    write_output(opts, |out| {
+        for (_, calls, _) in &traces {
+            for call in calls {
+                writeln!(out, "{} {:x} {:x}", call.cpu, call.from, call.to)?;
+            }
+        }
+        Ok(())
+    });
+    health
+}
+
+/// Print each `--addr` address as given alongside what it resolves to.
+fn lookup(opts: &Options, syms: &Syms, kallsyms_lines: &ParseStats) -> Health {
+    let percpu = percpu_range(syms);
+    let mut health = Health::with_kallsyms(kallsyms_lines);
+    write_output(opts, |out| {
+        for input in &opts.addrs {
+            let addr = match parse_hex(input) {
+                Some(addr) => addr,
+                None => {
+                    eprintln!("Warning: skipping {}, not a hex address", input);
+                    health.parse_errors += 1;
+                    continue;
+                }
+            };
+            let addr = opts.relocate(addr);
+            let resolved = match resolve(addr, syms, percpu, opts.output.exported_only) {
+                Some(sym) => opts.output.describe(&sym, syms),
+                None => {
+                    health.unresolved += 1;
+                    opts.output.unresolved(addr)
+                }
+            };
+            writeln!(out, "{} {}", input, resolved)?;
+        }
+        Ok(())
+    });
+    health
+}
+
+/// Pager run for --pager unless $PAGER is set
+const DEFAULT_PAGER: &str = "less -R";
+
+/// Write to a buffered stdout, exiting quietly if it's closed early, e.g.
+/// when piped to head. With --pager and stdout a terminal, write through the
+/// pager instead, the same way when the pager is quit before the end.
+fn write_output<F: FnOnce(&mut Box<dyn Write>) -> io::Result<()>>(opts: &Options, f: F) {
+    let mut pager = None;
+    let mut out: Box<dyn Write> = if opts.pager && !opts.watch && io::stdout().is_terminal() {
+        let command = env::var("PAGER").unwrap_or_else(|_| DEFAULT_PAGER.to_string());
+        let mut child = Command::new("sh")
+            .args(["-c", &command])
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap_or_else(|e| panic!("Failed to run pager {}: {}", command, e));
+        let stdin = child.stdin.take().unwrap();
+        pager = Some(child);
+        Box::new(BufWriter::new(stdin))
+    } else {
+        Box::new(BufWriter::new(io::stdout().lock()))
+    };
+    let result = f(&mut out).and_then(|_| out.flush());
+    // Closing the pager's input lets it finish
+    drop(out);
+    if let Some(mut pager) = pager {
+        pager.wait().expect("Failed to wait for pager");
+    }
+    match result {
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => process::exit(0),
+        Err(e) => panic!("Failed to write output: {}", e),
+        Ok(()) => (),
+    }
+}
+
+/// Keep the calls from the first call to --start-symbol through the last call
+/// to --stop-symbol after it. A start that's never called leaves nothing, a
+/// stop that isn't called after the start runs to the end.
+fn slice_calls(opts: &Options, mut calls: Vec<FnCall>, syms: &Syms) -> Vec<FnCall> {
+    let percpu = percpu_range(syms);
+    let calls_to = |call: &FnCall, name: &str| match call.to_name {
+        Some(ref to) => site_function(to) == name,
+        None => resolve(call.to, syms, percpu, opts.output.exported_only)
+            .is_some_and(|to| to.sym.name == name),
+    };
+    if let Some(ref start) = opts.start_symbol {
+        match calls.iter().position(|call| calls_to(call, start)) {
+            Some(first) => {
+                calls.drain(..first);
+            }
+            None => {
+                eprintln!("Warning: --start-symbol {} is never called", start);
+                calls.clear();
+                return calls;
+            }
+        }
+    }
+    if let Some(ref stop) = opts.stop_symbol {
+        match calls.iter().rposition(|call| calls_to(call, stop)) {
+            Some(last) => calls.truncate(last + 1),
+            None if opts.start_symbol.is_some() => {
+                eprintln!("Warning: --stop-symbol {} isn't called after --start-symbol {}, \
+                           keeping the calls to the end", stop,
+                          opts.start_symbol.as_ref().unwrap())
+            }
+            None => eprintln!("Warning: --stop-symbol {} is never called", stop),
+        }
+    }
+    calls
+}
+
+/// Read the traces and print the resolved calls.
+fn run(opts: &Options, kallsyms: &Kallsyms) -> Health {
+    let syms = &kallsyms.syms;
+    let mut health = Health::with_kallsyms(&kallsyms.lines);
+    let mut traces = Vec::new();
+    for (trace, mut calls, stats) in read_traces(opts, kallsyms.names.as_ref()) {
+        health.parse_errors += stats.errors;
+        health.trace_lines.add(&stats);
+        let inversions = timestamp_inversions(&calls);
+        if inversions > 0 && opts.sort_time {
+            eprintln!("Warning: sorting {} by timestamp, {} calls were out of order",
+                      trace, inversions);
+            // Stable, so calls with equal timestamps keep their order
+            calls.sort_by_key(|call| call.ts);
+        } else if inversions > 0 {
+            eprintln!("Warning: {} calls in {} are out of timestamp order, see --sort-time",
+                      inversions, trace);
+        }
+        traces.push(calls);
+    }
+    // Interleave per-CPU dumps into one timeline when they're timestamped
+    let mut calls = if traces.iter().flatten().all(|call| call.ts.is_some()) {
+        merge_by_timestamp(traces)
+    } else {
+        if traces.len() > 1 {
+            eprintln!("Warning: traces aren't all timestamped, concatenating in file order");
+        }
+        traces.into_iter().flatten().collect()
+    };
+    if let Some(slow) = opts.slow {
+        calls.retain(|call| call.duration.is_some_and(|d| d > slow));
+    }
+
+    if opts.byteswap {
+        // Swap within the width of the kernel's addresses so 32-bit values
+        // don't end up in the upper half of the u64
+        let width = detect_address_width(syms);
+        for call in &mut calls {
+            if !call.from_named {
+                call.from = byteswap(call.from, width);
+            }
+            if !call.to_named {
+                call.to = byteswap(call.to, width);
+            }
+        }
+    }
+    // A missing caller stays recognizable, and addresses looked up by name
+    // are already where the kallsyms has them
+    if let Some(offset) = opts.kaslr_offset {
+        for call in &mut calls {
+            if !call.from_named && !opts.output.none_addrs.contains(&call.from) {
+                call.from = call.from.saturating_sub(offset);
+            }
+            if !call.to_named {
+                call.to = call.to.saturating_sub(offset);
+            }
+        }
+    }
+    if let Some(offset) = opts.add_offset {
+        for call in &mut calls {
+            if !call.from_named && !opts.output.none_addrs.contains(&call.from) {
+                call.from = call.from.saturating_add(offset);
+            }
+            if !call.to_named {
+                call.to = call.to.saturating_add(offset);
+            }
+        }
+    }
+    if opts.sanity {
+        sanity_check(&calls, detect_address_width(syms));
+    }
+    if opts.start_symbol.is_some() || opts.stop_symbol.is_some() {
+        calls = slice_calls(opts, calls, syms);
+    }
+    if opts.cross_module {
+        // Core kernel symbols have no module, so calls between the kernel and
+        // a module count as crossing
+        calls.retain(|call| match (find_sym(call.to, syms), find_sym(call.from, syms)) {
+            (Some(to), Some(from)) => to.sym.module != from.sym.module,
+            _ => false,
+        });
+    }
+    if opts.hide_ftrace_internals {
+        let internal = |addr| find_sym(addr, syms).is_some_and(|s| is_ftrace_internal(s.sym));
+        calls.retain(|call| !internal(call.to) && !internal(call.from));
+    }
+    if opts.filter.is_some() || opts.glob.is_some() {
+        // A name must pass both filters when both are given
+        let matches = |name: &str| {
+            opts.filter.as_ref().is_none_or(|regex| regex.is_match(name)) &&
+                opts.glob.as_ref().is_none_or(|glob| glob_match(glob.as_bytes(), name.as_bytes()))
+        };
+        let percpu = percpu_range(syms);
+        let name_matches = |addr, name: &Option<String>| match *name {
+            Some(ref name) => matches(site_function(name)),
+            None => resolve(addr, syms, percpu, opts.output.exported_only)
+                .is_some_and(|s| matches(&s.sym.name)),
+        };
+        calls.retain(|call| {
+            name_matches(call.to, &call.to_name) || name_matches(call.from, &call.from_name)
+        });
+    }
+
+    let inlines = match opts.inlines {
+        Some(ref vmlinux) if !opts.reports() => {
+            let mut sites: Vec<u64> = calls.iter().map(|call| call.from).collect();
+            sites.sort();
+            sites.dedup();
+            addr2line_inlines(vmlinux, &sites)
+        }
+        _ => Inlines::new(),
+    };
+    let mut output = opts.output.clone();
+    output.percpu = percpu_range(syms);
+    output.text_start = text_start(syms);
+    output.fallback = match opts.addr2line {
+        Some(ref vmlinux) if !opts.reports() => {
+            let mut addrs: Vec<u64> = calls.iter()
+                .flat_map(|call| {
+                    let to = Some(call.to).filter(|_| opts.output.resolve_to);
+                    let from = Some(call.from).filter(|_| opts.output.resolve_from);
+                    to.into_iter().chain(from)
+                })
+                .filter(|&addr| output.resolve(addr, syms).is_none())
+                .collect();
+            addrs.sort();
+            addrs.dedup();
+            addr2line_names(vmlinux, &addrs)
+        }
+        _ => Fallback::new(),
+    };
+    let fallback = &output.fallback;
+
+    write_output(opts, |out| if opts.reports() {
+        print_reports(out, opts, &calls, syms)
+    } else if opts.format == Format::FoldedSamples {
+        print_folded_samples(out, &calls, syms)
+    } else {
+        let hits = print_calls(out, opts, &calls, syms, &inlines, &output)?;
+        health.line_cache_hits = Some(hits);
+        Ok(())
+    });
+
+    // Only endpoints that were meant to be resolved count, and names from
+    // the trace are printed as they are
+    let unresolved = |addr| output.resolve(addr, syms).is_none() && !fallback.contains_key(&addr);
+    health.unresolved = calls.iter()
+        .map(|call| {
+            (opts.output.resolve_to && call.to_name.is_none() && unresolved(call.to)) as usize +
+                (opts.output.resolve_from && call.from_name.is_none() &&
+                 !opts.output.none_addrs.contains(&call.from) && unresolved(call.from)) as usize
+        })
+        .sum();
+    if opts.stats {
+        print_stats(&calls, syms, &health, &opts.output);
+    }
+    if let Some(ref path) = opts.stats_json {
+        let json = Stats::new(opts, &calls, syms, &health).to_json();
+        fs::write(path, json + "\n").unwrap_or_else(|e| panic!("Failed to write {}: {}", path, e));
+    }
+    health
+}
+
+/// Summarize what was read and how well it resolved, on stderr so it doesn't
+/// mix with the output
+fn print_stats(calls: &[FnCall], syms: &Syms, health: &Health, output: &FormatOpts) {
+    let (_, underflows) = call_parents_with_underflows(calls, syms);
+    let per_cpu: Vec<String> = output.by_cpu_label(&underflows).iter()
+        .map(|(cpu, count)| format!("cpu {}: {}", cpu, count))
+        .collect();
+    eprintln!("kallsyms lines: {} ({} unparsed)", health.kallsyms_lines.total_lines,
+              health.kallsyms_lines.errors);
+    eprintln!("trace lines: {} ({} too long)", health.trace_lines.total_lines,
+              health.trace_lines.skipped);
+    eprintln!("calls: {}", calls.len());
+    eprintln!("parse errors: {}", health.parse_errors);
+    eprintln!("unresolved addresses: {}", health.unresolved);
+    if let Some(hits) = health.line_cache_hits {
+        let rate = if calls.is_empty() { 0.0 } else { hits as f64 * 100.0 / calls.len() as f64 };
+        eprintln!("line cache hits: {} ({:.1}%)", hits, rate);
+    }
+    if per_cpu.is_empty() {
+        eprintln!("stack underflows: 0");
+    } else {
+        eprintln!("stack underflows: {} ({})", underflows.values().sum::<usize>(),
+                  per_cpu.join(", "));
+    }
+}
+
+fn print_folded_samples<W: Write>(out: &mut W, calls: &[FnCall], syms: &Syms) -> io::Result<()> {
+    for (stack, count) in folded_samples(calls, syms) {
+        writeln!(out, "{} {}", stack, count)?;
+    }
+    Ok(())
+}
+
+/// Returns how many calls repeated an earlier (cpu, caller, callee) and so
+/// reused its line instead of resolving again.
+/// A call as far as its line goes: CPU, caller and callee addresses, and the
+/// names a debugfs trace gave them if they couldn't be looked up
+type CallKey<'a> = (u32, u64, u64, Option<&'a str>, Option<&'a str>);
+
+fn print_calls<W: Write>(out: &mut W, opts: &Options, calls: &[FnCall], syms: &Syms,
+                         inlines: &Inlines, output: &FormatOpts) -> io::Result<usize> {
+    if opts.header {
+        match opts.format {
+            Format::Text => {
+                writeln!(out, "# cpu  callee {} caller  (kallsyms: {}, trace: {}, {} calls)",
+                         opts.output.arrow, opts.kallsyms.join(", "), opts.traces.join(", "),
+                         calls.len())?
+            }
+            Format::Ftrace => writeln!(out, "# tracer: function\n#\n# CPU)  FUNCTION")?,
+            Format::FoldedSamples => unreachable!("folded samples have no header"),
+        }
+    }
+
+    // Search. Loops repeat the same calls over and over, so each distinct
+    // call is resolved and formatted once, along with whether it's a
+    // --split-on trigger.
+    let mut lines: HashMap<CallKey, (String, bool)> = HashMap::new();
+    let mut hits = 0;
+    let mut segment = 0;
     for call in calls {
-        let from = find_sym(call.from, &syms).unwrap();
-        let to = find_sym(call.to, &syms).unwrap();
-        println!("{} {} <- {}", call.cpu, to.sym, from);
+        let key = (call.cpu, call.from, call.to, call.from_name.as_deref(),
+                   call.to_name.as_deref());
+        let &mut (ref line, trigger) = match lines.entry(key) {
+            Entry::Occupied(entry) => {
+                hits += 1;
+                entry.into_mut()
+            }
+            Entry::Vacant(entry) => {
+                let trigger = opts.split_on.as_ref().is_some_and(|name| {
+                    match call.to_name {
+                        Some(ref to) => site_function(to) == name,
+                        None => resolve(call.to, syms, output.percpu, opts.output.exported_only)
+                            .is_some_and(|to| &to.sym.name == name),
+                    }
+                });
+                entry.insert((format_call(call, syms, output), trigger))
+            }
+        };
+        if trigger {
+            segment += 1;
+            // Ftrace parsers skip comments
+            let comment = if opts.format == Format::Ftrace { "# " } else { "" };
+            writeln!(out, "{}--- segment {} ---", comment, segment)?;
+        }
+        if opts.format == Format::Ftrace {
+            // Only what ftrace parsers expect: no durations or inline frames
+            writeln!(out, "{}", line)?;
+            continue;
+        }
+        if let Some(duration) = call.duration {
+            writeln!(out, "{} ({} us)", line, duration)?;
+        } else {
+            writeln!(out, "{}", line)?;
+        }
+        // A single frame means the call site wasn't inlined
+        if let Some(frames) = inlines.get(&call.from).filter(|frames| frames.len() > 1) {
+            for (function, location) in frames {
+                writeln!(out, "    {} at {}", function, location)?;
+            }
+        }
+    }
+    Ok(hits)
+}
+
+/// Print each selected report, separated by blank lines
+fn print_reports<W: Write>(out: &mut W, opts: &Options, calls: &[FnCall], syms: &Syms)
+                           -> io::Result<()> {
+    let mut first = true;
+    let mut separate = |out: &mut W| if first {
+        first = false;
+        Ok(())
+    } else {
+        writeln!(out)
+    };
+    if opts.tree {
+        separate(out)?;
+        let mut roots = build_call_tree(calls, syms);
+        if opts.fold_recursion {
+            fold_recursion(&mut roots);
+        }
+        print_tree(out, &roots, opts.ascii_tree, &opts.output)?;
+    }
+    if let Some(n) = opts.top_callees {
+        separate(out)?;
+        print_top(out, "callees", &top_callees(calls, syms, n, opts.offset_granularity))?;
+    }
+    if let Some(n) = opts.top_callers {
+        separate(out)?;
+        print_top(out, "callers", &top_callers(calls, syms, n, opts.offset_granularity))?;
+    }
+    if opts.time_by_symbol {
+        separate(out)?;
+        print_time_by_symbol(out, calls, syms, opts.offset_granularity)?;
+    }
+    Ok(())
+}
+
+/// Draw the call tree with box-drawing characters, or plain ASCII. Each root
+/// is headed by its CPU, or the CPU's label.
+fn print_tree<W: Write>(out: &mut W, roots: &[CallNode], ascii: bool, output: &FormatOpts)
+                        -> io::Result<()> {
+    let (branch, last, pipe) = if ascii {
+        ("|- ", "`- ", "|  ")
+    } else {
+        ("\u{251c}\u{2500} ", "\u{2514}\u{2500} ", "\u{2502}  ")
+    };
+    // Children to visit, last first, with the prefix drawn for their
+    // ancestors and whether each is the last child
+    fn children<'a>(node: &'a CallNode, prefix: &str) -> Vec<(&'a CallNode, String, bool)> {
+        let count = node.children.len();
+        node.children.iter().enumerate().rev()
+            .map(|(i, child)| (child, prefix.to_string(), i + 1 == count))
+            .collect()
+    }
+
+    fn name(node: &CallNode) -> String {
+        if node.calls > 1 {
+            format!("{} (recursion x{})", node.symbol, node.calls)
+        } else {
+            node.symbol.clone()
+        }
+    }
+
+    for root in roots {
+        writeln!(out, "{} {}", output.cpu_label(root.cpu), name(root))?;
+        // Walk with an explicit stack since traces can nest deeply
+        let mut stack = children(root, "");
+        while let Some((node, prefix, is_last)) = stack.pop() {
+            writeln!(out, "{}{}{}", prefix, if is_last { last } else { branch }, name(node))?;
+            let prefix = prefix + if is_last { "   " } else { pipe };
+            stack.extend(children(node, &prefix));
+        }
+    }
+    Ok(())
+}
+
+fn print_top<W: Write>(out: &mut W, what: &str, counts: &[(String, usize)]) -> io::Result<()> {
+    writeln!(out, "# top {} {}", counts.len(), what)?;
+    writeln!(out, "{:>8}  symbol", "calls")?;
+    for &(ref name, count) in counts {
+        writeln!(out, "{:>8}  {}", count, name)?;
+    }
+    Ok(())
+}
+
+fn print_time_by_symbol<W: Write>(out: &mut W, calls: &[FnCall], syms: &Syms,
+                                  granularity: Option<u64>) -> io::Result<()> {
+    if calls.iter().all(|call| call.duration.is_none()) {
+        eprintln!("Warning: the trace has no call durations to attribute");
+    }
+    writeln!(out, "{:>14} {:>14} {:>8}  symbol", "inclusive us", "exclusive us", "calls")?;
+    for time in time_by_symbol(calls, syms, granularity) {
+        writeln!(out, "{:>14.3} {:>14.3} {:>8}  {}",
+                 time.inclusive, time.exclusive, time.calls, time.name)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        glob_match(pattern.as_bytes(), name.as_bytes())
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(matches("*-0", "ftrace-ramoops-0"));
+        assert!(matches("ftrace-*-0", "ftrace-ramoops-0"));
+        assert!(matches("ftrace-*", "ftrace-ramoops-0"));
+        assert!(matches("*", ""));
+        assert!(matches("ftrace-ramoops-?", "ftrace-ramoops-7"));
+        assert!(!matches("ftrace-ramoops-?", "ftrace-ramoops-10"));
+        assert!(!matches("ftrace-*-1", "ftrace-ramoops-0"));
+        // The first * has to give back what it took for b to match
+        assert!(matches("a*b*c", "axbyc"));
+        assert!(matches("a*b*c", "abbbc"));
+        assert!(!matches("a*b*c", "axbyd"));
+    }
+
+    #[test]
+    fn start_and_stop() {
+        use ftrace_ramoops_fmt::{ftrace, kallsyms};
+        let syms = kallsyms(&b"1000 T a\n2000 T b\n3000 T c\n"[..], &ParseOpts::default());
+        let trace = "0 2000 3000 x\n0 1000 3000 x\n0 3000 1000 x\n0 2000 1000 x\n0 3000 2000 x\n";
+        let slice = |args: &[&str]| {
+            let args = args.iter().chain(&["kallsyms", "trace"]).map(|arg| arg.to_string());
+            let opts = Options::parse(args, Vec::new());
+            let calls = slice_calls(&opts, ftrace(trace.as_bytes(), &ParseOpts::default()), &syms);
+            calls.iter().map(|call| call.to).collect::<Vec<_>>()
+        };
+        assert_eq!(slice(&["--start-symbol", "a", "--stop-symbol", "b"]),
+                   vec![0x1000, 0x3000, 0x2000]);
+        assert_eq!(slice(&["--stop-symbol", "b"]), vec![0x2000, 0x1000, 0x3000, 0x2000]);
+        // b's calls after a are searched, not those before it
+        assert_eq!(slice(&["--start-symbol", "c", "--stop-symbol", "a"]), vec![0x3000, 0x2000,
+                                                                               0x3000]);
+        assert_eq!(slice(&["--start-symbol", "missing"]), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn glob_skips_dotfiles() {
+        let dir = env::temp_dir().join(format!("ftrace-ramoops-fmt-glob-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in &["trace-1", "trace-0", ".trace-2"] {
+            File::create(dir.join(name)).unwrap();
+        }
+        let dir = dir.to_str().unwrap();
+        assert_eq!(expand_glob(&format!("{}/*", dir)),
+                   vec![format!("{}/trace-0", dir), format!("{}/trace-1", dir)]);
+        assert_eq!(expand_glob(&format!("{}/.t*", dir)), vec![format!("{}/.trace-2", dir)]);
+        // No match leaves the path alone
+        assert_eq!(expand_glob(&format!("{}/x*", dir)), vec![format!("{}/x*", dir)]);
+        fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -0,0 +1,235 @@
+//! `serve`: resolve addresses over HTTP against symbols loaded once.
+//!
+//!     GET /resolve?addr=0xffffffff81000104
+//!     POST /resolve          (body: addresses as for --addr)
+//!
+//! GET answers with one JSON object, POST with an array of them, in the
+//! order the addresses were given.
+
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use ftrace_ramoops_fmt::{Syms, percpu_range, resolve};
+use {Options, json_string, parse_hex, split_addrs};
+
+/// Largest request body accepted
+const MAX_BODY: usize = 1024 * 1024;
+/// Largest request line and headers accepted, together
+const MAX_HEAD: u64 = 16 * 1024;
+/// How long a client may go without sending anything
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// Connections handled at once. Any more are turned away.
+const MAX_CONNECTIONS: usize = 64;
+
+pub fn serve(opts: &Options, syms: &Syms) -> ! {
+    let listener = TcpListener::bind(&opts.listen)
+        .unwrap_or_else(|e| panic!("Failed to listen on {}: {}", opts.listen, e));
+    if !opts.quiet {
+        eprintln!("Serving on http://{}/resolve", opts.listen);
+    }
+    // Finding the section means visiting every symbol, so do it once
+    let percpu = percpu_range(syms);
+    let open = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) if open.load(Ordering::SeqCst) >= MAX_CONNECTIONS => {
+                    let error = error_json("too many connections");
+                    if let Err(e) = respond(&stream, "503 Service Unavailable", &error) {
+                        eprintln!("Warning: request failed: {}", e);
+                    }
+                }
+                Ok(stream) => {
+                    open.fetch_add(1, Ordering::SeqCst);
+                    let open = &open;
+                    scope.spawn(move || {
+                        if let Err(e) = handle(stream, opts, syms, percpu) {
+                            eprintln!("Warning: request failed: {}", e);
+                        }
+                        open.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+                Err(e) => eprintln!("Warning: failed to accept connection: {}", e),
+            }
+        }
+    });
+    unreachable!("listener stopped accepting");
+}
+
+/// The method, path and query string of a request line such as
+/// `GET /resolve?addr=0x1000 HTTP/1.1`, each empty when missing
+fn parse_request_line(request: &str) -> (&str, &str, &str) {
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let target = parts.next().unwrap_or("");
+    let mut target = target.splitn(2, '?');
+    let path = target.next().unwrap();
+    let query = target.next().unwrap_or("");
+    (method, path, query)
+}
+
+/// The request line and Content-Length of a request, or None if they run
+/// past `MAX_HEAD` bytes
+fn read_head<R: BufRead>(reader: R) -> io::Result<Option<(String, usize)>> {
+    let mut head = reader.take(MAX_HEAD);
+    let mut request = String::new();
+    head.read_line(&mut request)?;
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if head.read_line(&mut header)? == 0 {
+            // The end of the input, or of what may be read of it
+            if head.limit() == 0 {
+                return Ok(None);
+            }
+            break;
+        }
+        if header.trim().is_empty() {
+            break;
+        }
+        let mut parts = header.splitn(2, ':');
+        let name = parts.next().unwrap().trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            length = parts.next().and_then(|v| v.trim().parse().ok()).unwrap_or(0);
+        }
+    }
+    Ok(Some((request, length)))
+}
+
+fn handle(stream: TcpStream, opts: &Options, syms: &Syms, percpu: Option<(u64, u64)>)
+          -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let (request, length) = match read_head(&mut reader) {
+        Ok(Some(head)) => head,
+        Ok(None) => {
+            let error = error_json("request line and headers too large");
+            respond(&stream, "431 Request Header Fields Too Large", &error)?;
+            // Closing with unread input would reset the connection before
+            // the client reads the answer. Whatever stops the draining, the
+            // answer is sent.
+            stream.shutdown(Shutdown::Write)?;
+            io::copy(&mut reader.take(MAX_BODY as u64), &mut io::sink()).ok();
+            return Ok(());
+        }
+        Err(ref e) if is_timeout(e) => {
+            return respond(&stream, "408 Request Timeout", &error_json("timed out"));
+        }
+        Err(e) => return Err(e),
+    };
+
+    let (method, path, query) = parse_request_line(&request);
+
+    let (status, body) = match (method, path) {
+        ("GET", "/resolve") => {
+            let addr = query.split('&').find_map(|param| param.strip_prefix("addr="));
+            match addr {
+                Some(addr) => ("200 OK", resolve_json(addr, opts, syms, percpu)),
+                None => ("400 Bad Request", error_json("missing addr parameter")),
+            }
+        }
+        ("POST", "/resolve") if length > MAX_BODY => {
+            ("413 Payload Too Large", error_json("request body too large"))
+        }
+        ("POST", "/resolve") => {
+            let mut body = vec![0; length];
+            match reader.read_exact(&mut body) {
+                Err(ref e) if is_timeout(e) => {
+                    return respond(&stream, "408 Request Timeout", &error_json("timed out"));
+                }
+                result => result?,
+            }
+            let body = String::from_utf8_lossy(&body);
+            let results: Vec<String> = split_addrs(&body).iter()
+                .map(|addr| resolve_json(addr, opts, syms, percpu))
+                .collect();
+            ("200 OK", format!("[{}]", results.join(",")))
+        }
+        (_, "/resolve") => ("405 Method Not Allowed", error_json("use GET or POST")),
+        _ => ("404 Not Found", error_json("not found")),
+    };
+
+    respond(&stream, status, &body)
+}
+
+/// Whether a read failed for going past `READ_TIMEOUT`, which is reported
+/// differently across platforms
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+fn respond(mut stream: &TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: application/json\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+           status, body.len() + 1, body)?;
+    stream.flush()
+}
+
+/// The answer for one address, e.g.
+/// `{"addr":"0xffffffff81000104","symbol":"foo","module":null,"offset":4,"percpu":false}`.
+/// `symbol` is null when the address doesn't resolve.
+fn resolve_json(input: &str, opts: &Options, syms: &Syms, percpu: Option<(u64, u64)>)
+                -> String {
+    let addr = match parse_hex(input) {
+        Some(addr) => opts.relocate(addr),
+        None => {
+            return format!("{{\"addr\":{},\"error\":\"not a hex address\"}}", json_string(input))
+        }
+    };
+    match resolve(addr, syms, percpu, opts.output.exported_only) {
+        Some(sym) => {
+            let module = sym.sym.module.as_ref().map_or("null".to_string(), |m| json_string(m));
+            format!("{{\"addr\":{},\"symbol\":{},\"module\":{},\"offset\":{},\"percpu\":{}}}",
+                    json_string(input), json_string(&sym.sym.name), module, sym.offset,
+                    sym.percpu)
+        }
+        None => format!("{{\"addr\":{},\"symbol\":null}}", json_string(input)),
+    }
+}
+
+fn error_json(message: &str) -> String {
+    format!("{{\"error\":{}}}", json_string(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ftrace_ramoops_fmt::{ParseOpts, kallsyms};
+
+    #[test]
+    fn request_head() {
+        let head = "POST /resolve HTTP/1.1\r\nHost: x\r\nContent-Length: 12\r\n\r\nbody";
+        assert_eq!(read_head(head.as_bytes()).unwrap(),
+                   Some(("POST /resolve HTTP/1.1\r\n".to_string(), 12)));
+        let long = format!("GET /resolve HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_HEAD as usize));
+        assert_eq!(read_head(long.as_bytes()).unwrap(), None);
+        let endless = format!("GET /{}", "a".repeat(2 * MAX_HEAD as usize));
+        assert_eq!(read_head(endless.as_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn request_line() {
+        assert_eq!(parse_request_line("GET /resolve?addr=0x1004 HTTP/1.1\r\n"),
+                   ("GET", "/resolve", "addr=0x1004"));
+        assert_eq!(parse_request_line("POST /resolve HTTP/1.1\r\n"), ("POST", "/resolve", ""));
+        assert_eq!(parse_request_line(""), ("", "", ""));
+    }
+
+    #[test]
+    fn resolve_answers() {
+        let text = "0000000000001000 T foo\n0000000000002000 t bar [ext4]\n";
+        let syms = kallsyms(text.as_bytes(), &ParseOpts::default());
+        let opts = Options::parse(vec!["serve".to_string(), "kallsyms".to_string()].into_iter(),
+                                  Vec::new());
+        let json = |addr| resolve_json(addr, &opts, &syms, None);
+        assert_eq!(json("0x1004"), "{\"addr\":\"0x1004\",\"symbol\":\"foo\",\"module\":null,\
+                                    \"offset\":4,\"percpu\":false}");
+        assert_eq!(json("2000"), "{\"addr\":\"2000\",\"symbol\":\"bar\",\"module\":\"ext4\",\
+                                  \"offset\":0,\"percpu\":false}");
+        assert_eq!(json("0x10"), "{\"addr\":\"0x10\",\"symbol\":null}");
+        assert_eq!(json("xyz"), "{\"addr\":\"xyz\",\"error\":\"not a hex address\"}");
+    }
+}
@@ -0,0 +1,112 @@
+//! `--stats-json`: the run's aggregates as one JSON object, for dashboards
+//! tracking boot health across builds.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use ftrace_ramoops_fmt::{FnCall, Syms, call_parents_with_underflows, find_sym, top_callees,
+                         top_callers};
+use {Health, Options, json_string};
+
+/// Entries in each top list
+const TOP: usize = 10;
+
+pub struct Stats {
+    trace_lines: usize,
+    calls: usize,
+    parse_errors: usize,
+    unresolved: usize,
+    /// Share of the endpoints meant to be resolved that didn't
+    unresolved_percent: f64,
+    /// Keyed by CPU label, as are the stack underflows
+    per_cpu: Vec<(String, usize)>,
+    top_callees: Vec<(String, usize)>,
+    top_callers: Vec<(String, usize)>,
+    /// Caller offsets into their function, by power of two: the count at 0x10
+    /// is of offsets 0x10 to 0x1f
+    offsets: BTreeMap<u64, usize>,
+    max_depth: usize,
+    mean_depth: f64,
+    stack_underflows: Vec<(String, usize)>,
+}
+
+impl Stats {
+    pub fn new(opts: &Options, calls: &[FnCall], syms: &Syms, health: &Health) -> Stats {
+        let mut per_cpu = BTreeMap::new();
+        for call in calls {
+            *per_cpu.entry(call.cpu).or_insert(0) += 1;
+        }
+        let mut offsets = BTreeMap::new();
+        for sym in calls.iter().filter_map(|call| find_sym(call.from, syms)) {
+            let bucket = if sym.offset == 0 { 0 } else { 1 << (63 - sym.offset.leading_zeros()) };
+            *offsets.entry(bucket).or_insert(0) += 1;
+        }
+        // Parents come before their children, so each depth is known by the
+        // time it's needed
+        let (parents, stack_underflows) = call_parents_with_underflows(calls, syms);
+        let mut depths: Vec<usize> = Vec::with_capacity(calls.len());
+        for parent in parents {
+            let depth = parent.map_or(0, |parent| depths[parent] + 1);
+            depths.push(depth);
+        }
+        let endpoints = calls.len() *
+            (opts.output.resolve_to as usize + opts.output.resolve_from as usize);
+        Stats {
+            trace_lines: health.trace_lines.total_lines,
+            calls: calls.len(),
+            parse_errors: health.parse_errors,
+            unresolved: health.unresolved,
+            unresolved_percent: percent(health.unresolved, endpoints),
+            per_cpu: opts.output.by_cpu_label(&per_cpu),
+            top_callees: top_callees(calls, syms, TOP, opts.offset_granularity),
+            top_callers: top_callers(calls, syms, TOP, opts.offset_granularity),
+            offsets,
+            max_depth: depths.iter().cloned().max().unwrap_or(0),
+            mean_depth: depths.iter().sum::<usize>() as f64 / depths.len().max(1) as f64,
+            stack_underflows: opts.output.by_cpu_label(&stack_underflows),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let fields = [
+            ("trace_lines", self.trace_lines.to_string()),
+            ("calls", self.calls.to_string()),
+            ("parse_errors", self.parse_errors.to_string()),
+            ("unresolved", self.unresolved.to_string()),
+            ("unresolved_percent", format!("{:.3}", self.unresolved_percent)),
+            ("per_cpu", object(self.per_cpu.iter().cloned())),
+            ("top_callees", top_json(&self.top_callees)),
+            ("top_callers", top_json(&self.top_callers)),
+            ("offset_histogram", object(self.offsets.iter()
+                .map(|(bucket, count)| (format!("0x{:x}", bucket), count)))),
+            ("max_depth", self.max_depth.to_string()),
+            ("mean_depth", format!("{:.3}", self.mean_depth)),
+            ("stack_underflows", object(self.stack_underflows.iter().cloned())),
+        ];
+        let fields: Vec<String> = fields.iter()
+            .map(|(name, value)| format!("{}:{}", json_string(name), value))
+            .collect();
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+fn percent(count: usize, total: usize) -> f64 {
+    if total == 0 { 0.0 } else { count as f64 * 100.0 / total as f64 }
+}
+
+/// Pairs as a JSON object of numbers, in the order given
+fn object<K: Display, V: Display, I: IntoIterator<Item = (K, V)>>(entries: I) -> String {
+    let entries: Vec<String> = entries.into_iter()
+        .map(|(key, value)| format!("{}:{}", json_string(&key.to_string()), value))
+        .collect();
+    format!("{{{}}}", entries.join(","))
+}
+
+/// A top list as an array of `{"symbol":...,"calls":...}`, most first
+fn top_json(top: &[(String, usize)]) -> String {
+    let entries: Vec<String> = top.iter()
+        .map(|(symbol, calls)| {
+            format!("{{\"symbol\":{},\"calls\":{}}}", json_string(symbol), calls)
+        })
+        .collect();
+    format!("[{}]", entries.join(","))
+}
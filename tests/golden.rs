@@ -0,0 +1,104 @@
+//! Run the whole pipeline on the fixtures and compare with the expected
+//! output. After an intended change to the output, regenerate it with
+//!
+//!     cargo run -- --quiet --no-config tests/fixtures/kallsyms.txt tests/fixtures/trace.txt \
+//!         > tests/fixtures/expected.txt
+
+use std::fs;
+use std::process::Command;
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+#[test]
+fn golden_output() {
+    let output = Command::new(env!("CARGO_BIN_EXE_ftrace-ramoops-fmt"))
+        .arg("--quiet")
+        .arg("--no-config")
+        .arg(format!("{}/kallsyms.txt", FIXTURES))
+        .arg(format!("{}/trace.txt", FIXTURES))
+        .output()
+        .expect("Failed to run ftrace-ramoops-fmt");
+    assert!(output.status.success(), "exited with {}: {}", output.status,
+            String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), include_str!("fixtures/expected.txt"));
+}
+
+#[test]
+fn config_defaults() {
+    // The config file in the working directory sets the defaults, options in
+    // the environment override it, and the command line overrides both
+    let dir = std::env::temp_dir().join(format!("ftrace-ramoops-fmt-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("ftrace-ramoops-fmt.toml"), format!(
+        "kallsyms = \"{}/kallsyms.txt\"\nquiet = true\nformat = 'ftrace'\narrow = \"=>\"\n",
+        FIXTURES)).unwrap();
+    let first_line = |env: &str, args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_ftrace-ramoops-fmt"))
+            .current_dir(&dir)
+            .env("FTRACE_RAMOOPS_FMT_OPTS", env)
+            .args(args)
+            .arg(format!("{}/trace.txt", FIXTURES))
+            .output()
+            .expect("Failed to run ftrace-ramoops-fmt");
+        assert!(output.status.success(), "exited with {}: {}", output.status,
+                String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap().lines().next().unwrap().to_string()
+    };
+    assert_eq!(first_line("", &[]), "  0) vfs_read <- do_syscall_64+0x34");
+    assert_eq!(first_line("--format text", &[]), "0 vfs_read => do_syscall_64+0x34");
+    assert_eq!(first_line("--format text", &["--arrow", "<="]), "0 vfs_read <= do_syscall_64+0x34");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn config_kallsyms_argument() {
+    // A kallsyms given as the first argument wins over the config file's
+    let dir = std::env::temp_dir().join(format!("ftrace-ramoops-fmt-ks-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("ftrace-ramoops-fmt.toml"), "kallsyms = \"missing\"\nquiet = true\n")
+        .unwrap();
+    let run = |args: &[String]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_ftrace-ramoops-fmt"))
+            .current_dir(&dir)
+            .env_remove("FTRACE_RAMOOPS_FMT_OPTS")
+            .args(args)
+            .output()
+            .expect("Failed to run ftrace-ramoops-fmt");
+        assert!(output.status.success(), "exited with {}: {}", output.status,
+                String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let kallsyms = format!("{}/kallsyms.txt", FIXTURES);
+    let trace = format!("{}/trace.txt", FIXTURES);
+    assert_eq!(run(&[kallsyms.clone(), trace]), include_str!("fixtures/expected.txt"));
+    run(&["--build-index".to_string(), "out.idx".to_string(), kallsyms]);
+    assert!(dir.join("out.idx").is_file());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn debugfs_names() {
+    // Functions a debugfs trace names are looked up under the kernel's own
+    // names, and aren't relocated again afterwards
+    let dir = std::env::temp_dir().join(format!("ftrace-ramoops-fmt-dbg-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("kallsyms"), "ffffffff81000000 T _stext\n\
+                                     ffffffff81000100 T do_syscall_64\n\
+                                     ffffffff81000200 t vfs_read.isra.0\n").unwrap();
+    fs::write(dir.join("trace"), "bash-1 [002] 1.000001: vfs_read.isra.0 <-do_syscall_64+0x34\n\
+                                  bash-1 [002] 1.000002: vfs_read.isra.0 <-0xffffffff81001134\n")
+        .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_ftrace-ramoops-fmt"))
+        .args(["--quiet", "--no-config", "--input-format", "debugfs", "--merge-suffixes",
+               "--kaslr-offset", "0x1000"])
+        .arg(dir.join("kallsyms"))
+        .arg(dir.join("trace"))
+        .output()
+        .expect("Failed to run ftrace-ramoops-fmt");
+    assert!(output.status.success(), "exited with {}: {}", output.status,
+            String::from_utf8_lossy(&output.stderr));
+    // The caller given as an address is still relocated
+    assert_eq!(String::from_utf8(output.stdout).unwrap(),
+               "2 vfs_read <- do_syscall_64+0x34\n2 vfs_read <- do_syscall_64+0x34\n");
+    fs::remove_dir_all(&dir).unwrap();
+}